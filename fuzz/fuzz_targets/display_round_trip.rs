@@ -0,0 +1,32 @@
+// Check that parse_display() and normalize_display() undo format_display_brackets(), and that
+// normalizing any display string is stable. Run with `cargo +nightly fuzz run display_round_trip`
+
+#![no_main]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use libfuzzer_sys::fuzz_target;
+use wsl2_get_display::{format_display_brackets, normalize_display, parse_display};
+
+fuzz_target!(|data: ([u8; 16], u8, u16, Option<u16>, bool, &str)| {
+    let (bytes, kind, display, screen, brackets, s) = data;
+    let host = match kind % 3 {
+        0 => None,
+        1 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        _ => Some(IpAddr::V6(Ipv6Addr::from(bytes))),
+    };
+    let formatted = format_display_brackets(host, display, screen, brackets);
+    let (parsed_host, parsed_display, parsed_screen) =
+        parse_display(&formatted).expect("formatted display doesn't parse");
+    assert_eq!(parsed_host.parse().ok(), host, "host of {formatted}");
+    assert_eq!((parsed_display, parsed_screen), (display, screen), "numbers of {formatted}");
+    let normalized = normalize_display(&formatted).expect("formatted display doesn't normalize");
+    if brackets || !matches!(host, Some(IpAddr::V6(_))) {
+        assert_eq!(normalized, formatted);
+    }
+
+    if let Ok(normalized) = normalize_display(s) {
+        let again = normalize_display(&normalized).expect("normalized display doesn't parse");
+        assert_eq!(again, normalized, "normalizing {s:?} isn't stable");
+    }
+});
@@ -0,0 +1,10 @@
+// Fuzz the parser for `ip -4 -json addr show dev <dev>` output. Run with
+// `cargo +nightly fuzz run parse_addr_json`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = wsl2_get_display::parse_addr_json(data);
+});
@@ -0,0 +1,12 @@
+// Fuzz the DNS response parser used with --dns-server. Run with
+// `cargo +nightly fuzz run parse_dns_response`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // use the ID from the data itself, so that the rest of the parser gets exercised too
+    let id = data.get(..2).map_or(0, |b| u16::from_be_bytes([b[0], b[1]]));
+    let _ = wsl2_get_display::parse_dns_response(data, id);
+});
@@ -0,0 +1,16 @@
+// Fuzz the `ip -json neigh` and /proc/net/arp parsers. Run with
+// `cargo +nightly fuzz run parse_neighbors`
+
+#![no_main]
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let ip = IpAddr::V4(Ipv4Addr::new(172, 16, 0, 1));
+    let _ = wsl2_get_display::parse_neigh_json(data, ip);
+    if let Ok(contents) = std::str::from_utf8(data) {
+        let _ = wsl2_get_display::parse_proc_arp(contents, ip);
+    }
+});
@@ -0,0 +1,9 @@
+// Fuzz the /proc/net/route parser. Run with `cargo +nightly fuzz run parse_proc_route`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = wsl2_get_display::parse_proc_route(data);
+});
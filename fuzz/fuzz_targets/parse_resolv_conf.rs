@@ -0,0 +1,10 @@
+// Fuzz the /etc/resolv.conf parser. Run with `cargo +nightly fuzz run parse_resolv_conf`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = wsl2_get_display::parse_resolv_conf(data);
+    let _ = wsl2_get_display::parse_resolv_conf_nameservers(data);
+});
@@ -0,0 +1,10 @@
+// Fuzz the parser for `ip -json route show default` output. Run with
+// `cargo +nightly fuzz run parse_route_json`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = wsl2_get_display::parse_route_json(data);
+});
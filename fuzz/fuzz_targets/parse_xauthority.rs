@@ -0,0 +1,9 @@
+// Fuzz the Xauthority file parser. Run with `cargo +nightly fuzz run parse_xauthority`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = wsl2_get_display::parse_xauthority(data);
+});
@@ -0,0 +1,1371 @@
+//! Command line arguments, which double as the options for the library API
+
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+
+use crate::{parse_display, DISPLAY_PORT_OFFSET};
+
+/// The longest connection timeout --fast uses. The host is on a virtual network, so it answers or
+/// refuses in well under a millisecond when it's reachable at all.
+const FAST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Kinds of local X server Unix sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum XSocket {
+    Fs,
+    Abstract,
+    Auto,
+}
+
+/// Ways to determine the WSL host's IP address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Method {
+    /// Gateway of the default route from `ip route`
+    Route,
+    /// First nameserver in /etc/resolv.conf
+    ResolvConf,
+    /// Look up the name given with --hostname
+    Hostname,
+    /// Gateway of the --bridge-interface, or the first address of its subnet
+    Bridge,
+    /// Gateway of the default route in /proc/net/route, which is quicker than running `ip`
+    ProcRoute,
+}
+
+impl Method {
+    /// The method's name as given on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Route => "route",
+            Method::ResolvConf => "resolv-conf",
+            Method::Hostname => "hostname",
+            Method::Bridge => "bridge",
+            Method::ProcRoute => "proc-route",
+        }
+    }
+
+    /// The method's one-line description, from its --help text
+    pub fn description(self) -> String {
+        self.to_possible_value()
+            .and_then(|v| v.get_help().map(ToString::to_string))
+            .unwrap_or_default()
+    }
+}
+
+/// Shells with different syntax for --export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    /// sh, bash, zsh and friends: `export VAR='value'`
+    Sh,
+    /// `set -gx VAR 'value'`
+    Fish,
+    /// csh and tcsh: `setenv VAR 'value'`
+    Csh,
+}
+
+/// IP address families, for --prefer and --lead-family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    /// Whether `ip` belongs to this family
+    pub fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::Ipv4 => ip.is_ipv4(),
+            IpFamily::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Which host to use when both IPv4 and IPv6 ones have an X server, for --dual-stack-policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DualStackPolicy {
+    /// The first IPv4 host to connect, or the first IPv6 one if none did
+    PreferV4,
+    /// The first IPv6 host to connect, or the first IPv4 one if none did
+    PreferV6,
+    /// Whichever host connected with the lowest latency
+    Fastest,
+    /// The first of each, printing the IPv6 DISPLAY on a second line (OTHER_DISPLAY with
+    /// --export)
+    Both,
+}
+
+/// Canned connection results for --simulate
+#[cfg(feature = "simulate")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Simulate {
+    Ok,
+    Refused,
+    Timeout,
+    Unreachable,
+}
+
+/// When to color output, for --color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Only when stderr is a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Formats for the --report document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Toml,
+    Json,
+}
+
+/// The subcommands, each selecting a mode. Options only used by one mode belong to its
+/// subcommand, options for detection and output go before the subcommand, like
+/// `wsl2-get-display -t 200 watch 5s`.
+#[derive(Debug, Clone, Subcommand)]
+pub enum Mode {
+    /// Find an X server and print its DISPLAY, the same as no subcommand
+    Find,
+    /// Only set the exit status, like --probe-only
+    Check,
+    /// Find an X server with debug output, and a hint about what to fix if there isn't one
+    Doctor,
+    /// Keep running and print the output every time it changes
+    Watch(WatchArgs),
+    /// Print a full report of the detection run for attaching to bug reports
+    Report(ReportArgs),
+    /// Print a completion script for a shell
+    Completions(CompletionsArgs),
+}
+
+/// The options of the watch subcommand
+#[derive(Debug, Clone, clap::Args)]
+pub struct WatchArgs {
+    /// How often to redo detection, e.g. "5s" (plain numbers are milliseconds). Detection also
+    /// reruns as soon as /etc/resolv.conf changes, which WSL does when the host IP changes.
+    #[arg(default_value = "5s", value_parser = parse_duration)]
+    pub interval: Duration,
+
+    /// Also write each change to this FIFO (named pipe), creating it if needed, so another
+    /// process can block reading it. Changes are skipped while nothing is reading.
+    #[arg(long, value_name = "PATH")]
+    pub fifo: Option<PathBuf>,
+}
+
+/// The options of the report subcommand
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReportArgs {
+    /// The format of the report
+    #[arg(value_enum, default_value_t = ReportFormat::Toml)]
+    pub format: ReportFormat,
+}
+
+/// The options of the completions subcommand
+#[derive(Debug, Clone, clap::Args)]
+pub struct CompletionsArgs {
+    /// The shell to print a completion script for
+    pub shell: clap_complete::Shell,
+}
+
+/// How to write IP addresses, for --ip-format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IpFormat {
+    /// The usual form, like 172.16.0.1 or fd00::1
+    Dotted,
+    /// The address as one decimal integer, like 2886729729
+    Int,
+    /// The reverse DNS name, like 1.0.16.172.in-addr.arpa
+    Arpa,
+}
+
+/// The order to probe the --scan displays in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScanOrder {
+    /// The display from $DISPLAY first, then 0 and 1, then the rest in the order given
+    Likely,
+    /// Exactly the order given
+    Given,
+}
+
+/// How to talk to the X server when probing, for --probe-protocol
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProbeProtocol {
+    /// Plain X over TCP
+    Raw,
+    /// X inside TLS, e.g. from stunnel
+    Tls,
+}
+
+/// A list of X display numbers, parsed from comma-separated numbers and `A..B` or `A..=B` ranges
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayList(pub Vec<u16>);
+
+impl FromStr for DisplayList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| n.trim().parse::<u16>().map_err(|e| format!("'{}': {}", n.trim(), e));
+        let mut displays = Vec::new();
+        for item in s.split(',') {
+            if let Some((start, end)) = item.split_once("..=") {
+                displays.extend(parse(start)?..=parse(end)?);
+            } else if let Some((start, end)) = item.split_once("..") {
+                displays.extend(parse(start)?..parse(end)?);
+            } else {
+                displays.push(parse(item)?);
+            }
+        }
+        if displays.is_empty() {
+            return Err(format!("no displays in '{}'", s));
+        }
+        Ok(DisplayList(displays))
+    }
+}
+
+/// The delays between connection attempts for --retry-schedule, parsed from a comma-separated
+/// list of durations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetrySchedule(pub Vec<Duration>);
+
+impl FromStr for RetrySchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let delays =
+            s.split(',').map(|item| parse_duration(item.trim())).collect::<Result<Vec<_>, _>>()?;
+        if delays.len() >= usize::from(u16::MAX) {
+            return Err(format!("too many delays in '{}'", s));
+        }
+        Ok(RetrySchedule(delays))
+    }
+}
+
+/// Which connection attempts get logged, for --attempts-log-level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptLogLevel {
+    /// Every attempt
+    All,
+    /// The first attempt, and later ones that fail with a different error than the last
+    Changes,
+    /// Every Nth attempt, plus the first and last
+    Every(u16),
+}
+
+impl FromStr for AttemptLogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(AttemptLogLevel::All),
+            "changes" => Ok(AttemptLogLevel::Changes),
+            _ => match s.parse::<u16>() {
+                Ok(0) | Err(_) => Err(format!("expected all, changes, or a number above 0: '{s}'")),
+                Ok(n) => Ok(AttemptLogLevel::Every(n)),
+            },
+        }
+    }
+}
+
+/// An IP subnet in CIDR notation like `172.16.0.0/12`, for --allow-subnet. A bare address is a
+/// subnet of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Subnet {
+    /// Whether `ip` is in this subnet. IPv4 and IPv6 addresses never match each other.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // compare the leading prefix_len bits, shifting by the full width would overflow
+        let mask = |bits: u32, width: u32| u128::MAX.checked_shl(width - bits).unwrap_or(0);
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(self.prefix_len.into(), 32) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(self.prefix_len.into(), 128);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.parse().map_err(|e| format!("'{}': {}", addr, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_len,
+            Some(prefix_len) => {
+                prefix_len.parse().ok().filter(|&len| len <= max_len).ok_or_else(|| {
+                    format!("prefix length must be 0 to {max_len}, not '{prefix_len}'")
+                })?
+            }
+        };
+        Ok(Subnet { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Parse a count that has to be at least 1, like --retries where zero would mean never trying
+fn parse_nonzero(s: &str) -> Result<u16, String> {
+    match s.parse::<u16>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        result => result.map_err(|e| e.to_string()),
+    }
+}
+
+/// Parse the display number, allowing a leading colon like in DISPLAY
+fn parse_display_number(s: &str) -> Result<u16, String> {
+    s.strip_prefix(':').unwrap_or(s).parse::<u16>().map_err(|e| e.to_string())
+}
+
+/// Parse a --timeout-fraction, which has to be more than 0 and at most 1
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if f > 0.0 && f <= 1.0 => Ok(f),
+        Ok(_) => Err("must be more than 0 and at most 1".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a duration given as milliseconds, or with an "ms" or "s" suffix
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = |e: &dyn std::fmt::Display| format!("invalid duration '{}': {}", s, e);
+    if let Some(ms) = s.strip_suffix("ms") {
+        ms.parse().map(Duration::from_millis).map_err(|e| err(&e))
+    } else if let Some(secs) = s.strip_suffix('s') {
+        Duration::try_from_secs_f64(secs.parse().map_err(|e| err(&e))?).map_err(|e| err(&e))
+    } else {
+        s.parse().map(Duration::from_millis).map_err(|e| err(&e))
+    }
+}
+
+/// Find an X server running on the WSL2 host.
+///
+/// wsl-get-display will infer the WSL2 hypervisor IP by finding the first nameserver in
+/// /etc/resolv.conf, then attempts a TCP connection on the appropriate port (6000
+/// + display_number)
+///
+/// The timeout, retries, method, and display number can also be set with the WSL2_DISPLAY_*
+/// environment variables listed below. Options given on the command line take precedence over the
+/// environment.
+///
+/// Options can also be read from a file by passing `@FILE`, which is replaced by the file's
+/// lines, one argument per line.
+#[derive(Debug, Clone, Parser)]
+#[command(version, max_term_width = 80)]
+pub struct Args {
+    /// Connection timeout in milliseconds, for each individual connection attempt. See also
+    /// --timeout-per-candidate and --budget, which limit the time spent across several attempts.
+    #[arg(short, long, default_value = "500", env = "WSL2_DISPLAY_TIMEOUT")]
+    #[arg(value_parser = |s: &str| s.parse().map(Duration::from_millis))]
+    pub timeout: Duration,
+
+    /// Number of connection attempts to make, at least 1. There's no unlimited setting, to keep
+    /// trying for a while use a large number and limit the total time with --budget.
+    #[arg(short, long, default_value = "1", env = "WSL2_DISPLAY_RETRIES")]
+    #[arg(value_parser = parse_nonzero)]
+    pub retries: u16,
+
+    /// Wait exactly these delays between connection attempts, as a comma-separated list like
+    /// "100,200,500,1s" (plain numbers are milliseconds). There's one more attempt than there are
+    /// delays. Overrides --retries, --refused-wait, and --jitter.
+    #[arg(long, value_name = "DELAYS")]
+    pub retry_schedule: Option<RetrySchedule>,
+
+    /// How long to wait before retrying after a connection is refused, e.g. "100ms" or "1s"
+    /// (plain numbers are milliseconds). Defaults to the --timeout.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    pub refused_wait: Option<Duration>,
+
+    /// Maximum random delay in milliseconds added between retries, so that many processes polling
+    /// at once don't stay in lockstep
+    #[arg(short, long, default_value = "0")]
+    #[arg(value_parser = |s: &str| s.parse().map(Duration::from_millis))]
+    pub jitter: Duration,
+
+    /// Enables verbose debug output on stderr. Give twice to also dump the raw contents of
+    /// /etc/resolv.conf, which may include private DNS search domains.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Which connection attempts to log with --verbose, to keep runs with lots of retries
+    /// readable: all of them, every Nth one (and the first and last), or just the first and any
+    /// that fail differently from the one before.
+    #[arg(long, value_name = "all|changes|N", default_value = "all")]
+    pub attempts_log_level: AttemptLogLevel,
+
+    /// X display number, e.g. the "1" in "localhost:1". It can be given as ":1" too, like in
+    /// DISPLAY.
+    #[arg(default_value = "1", env = "WSL2_DISPLAY_NUMBER", value_parser = parse_display_number)]
+    pub display_number: u16,
+
+    /// X screen number to append to the output, e.g. the "0" in "localhost:1.0"
+    #[arg(short, long)]
+    pub screen: Option<u16>,
+
+    /// Probe each of these display numbers in turn rather than just DISPLAY_NUMBER, and use the
+    /// first one found. A comma-separated list of numbers and ranges, e.g. "0..10" or "0,2..=4".
+    #[arg(long, value_name = "DISPLAYS")]
+    pub scan: Option<DisplayList>,
+
+    /// If DISPLAY_NUMBER isn't found, also try DISPLAY_NUMBER plus this offset, for a second X
+    /// server on a different display. The output shows which one answered.
+    #[arg(long, value_name = "N", conflicts_with = "scan")]
+    pub display_offset: Option<u16>,
+
+    /// If DISPLAY_NUMBER isn't found, also try the ports up to N either side of its port, nearest
+    /// first, for forwarders that map X to a slightly different port. The output uses the display
+    /// of the port that answered, with a note when it isn't DISPLAY_NUMBER.
+    #[arg(long, value_name = "N", default_value = "0")]
+    #[arg(conflicts_with_all = ["scan", "local", "unix_socket", "port"])]
+    pub port_fuzz: u16,
+
+    /// The order to probe the --scan displays in. With likely, the ones most likely to have an X
+    /// server are tried first, so the usual cases are found quickly within a --budget.
+    #[arg(long, value_enum, default_value_t = ScanOrder::Likely, value_name = "ORDER")]
+    pub scan_order: ScanOrder,
+
+    /// Probe the display numbers that have entries in the Xauthority file ($XAUTHORITY or
+    /// ~/.Xauthority) in turn, like --scan. DISPLAY_NUMBER is used if it has none.
+    #[arg(long, conflicts_with = "scan")]
+    pub from_xauth: bool,
+
+    /// Limit on the total time spent probing, across all retries and --scan displays, e.g. "2s"
+    /// or "1500ms" (plain numbers are milliseconds). Connection timeouts are cut short to fit.
+    #[arg(long, visible_alias = "timeout-total", value_name = "TIME")]
+    #[arg(value_parser = parse_duration)]
+    pub budget: Option<Duration>,
+
+    /// Limit on the time spent probing each --scan display, across all of its retries, before
+    /// moving on to the next one. Accepts the same format as --budget, and is itself cut short by
+    /// the time left in the --budget.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    pub timeout_per_candidate: Option<Duration>,
+
+    /// Make each connection attempt's timeout this fraction of the time left in the --budget (or
+    /// --timeout-per-candidate), rather than the fixed --timeout. Early attempts fail fast and
+    /// later ones get whatever time is left. Ignored without a time limit.
+    #[arg(long, value_name = "F", value_parser = parse_fraction)]
+    pub timeout_fraction: Option<f64>,
+
+    /// With the resolv-conf and hostname methods, prefer addresses of this family when both are
+    /// found. Normally the first one listed is used.
+    #[arg(long, value_enum, value_name = "FAMILY")]
+    pub prefer: Option<IpFamily>,
+
+    /// The host's name, for the hostname method (e.g. the Windows computer name, or
+    /// "<name>.local")
+    #[arg(long, value_name = "NAME")]
+    pub hostname: Option<String>,
+
+    /// With the hostname method, query this DNS server directly rather than using the system
+    /// resolver, which can hang when /etc/resolv.conf is broken. Limited by --timeout.
+    #[arg(long, value_name = "IP")]
+    pub dns_server: Option<IpAddr>,
+
+    /// Only accept host IPs in this subnet, e.g. 172.16.0.0/12, refusing to connect to anything
+    /// else. Can be given more than once to allow several subnets.
+    #[arg(long, value_name = "CIDR")]
+    pub allow_subnet: Vec<Subnet>,
+
+    /// Accept a loopback address like 127.0.0.1 from detection. These are normally rejected,
+    /// falling through to the next method, since outside of WSL's mirrored networking mode they
+    /// come from a local stub resolver rather than the host.
+    #[arg(long)]
+    pub allow_loopback: bool,
+
+    /// Fail rather than warn when not running in WSL, where host IP detection finds something
+    /// that isn't a Windows host
+    #[arg(long)]
+    pub require_wsl: bool,
+
+    /// Skip the check for running in WSL, e.g. for testing in a container
+    #[arg(long, env = "WSL2_DISPLAY_ASSUME_WSL", conflicts_with = "require_wsl")]
+    #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+    pub assume_wsl: bool,
+
+    /// Use this host IP address rather than detecting it with any of the --method list
+    #[arg(long, value_name = "IP")]
+    pub host: Option<IpAddr>,
+
+    /// Read the host IP address from the first line of stdin rather than detecting it, for
+    /// pipelines like `find-the-host | wsl2-get-display --host-from-stdin`. The line can also be a
+    /// whole display like 172.16.0.1:1, which overrides the display (and screen) number too.
+    #[arg(long, conflicts_with_all = ["host", "interactive", "local", "unix_socket", "serve"])]
+    pub host_from_stdin: bool,
+
+    /// Probe each of the host IP addresses listed in this file in turn, rather than detecting the
+    /// host, and use the first with an X server (or race them all with --parallel). The file has
+    /// one address per line, where blank lines, `#` comments and lines that aren't an address
+    /// (with a warning) are skipped.
+    #[arg(long, value_name = "PATH")]
+    #[arg(conflicts_with_all = ["host", "host_from_stdin", "local", "unix_socket"])]
+    pub host_file: Option<PathBuf>,
+
+    /// Connect to this TCP port rather than 6000 plus the display number. The printed DISPLAY
+    /// still uses the display number, so this is only for testing the probe against a server on
+    /// an arbitrary port, like a fake X server listening on an ephemeral port.
+    #[arg(long, value_name = "PORT", hide = true)]
+    pub port: Option<u16>,
+
+    /// Once an X server answers, go through the X11 connection setup with it and check the reply,
+    /// to make sure it's really an X server and see which one. The vendor and version are shown
+    /// with --verbose and included in --json output. Only for X servers on the host.
+    #[arg(long, visible_alias = "validate-handshake-reply")]
+    #[arg(conflicts_with_all = ["local", "unix_socket", "no_validate"])]
+    pub handshake: bool,
+
+    /// Only count a connection as finding an X server once it has gone through the X11
+    /// connection setup, so that anything else listening on the port (like a stale SSH forward)
+    /// isn't mistaken for one. Each attempt then takes a round trip longer, plus however long a
+    /// non-X peer takes to be given up on (see --read-timeout).
+    #[arg(long, conflicts_with_all = ["local", "unix_socket", "no_validate"])]
+    pub strict_connect: bool,
+
+    /// Only count an X server as found once this shell command, run with DISPLAY set to it, exits
+    /// successfully, e.g. "xset q" to check that a real X client can use it. A command that fails
+    /// or takes longer than --validate-timeout is retried like a refused connection.
+    #[arg(long, value_name = "COMMAND")]
+    #[arg(conflicts_with_all = ["local", "unix_socket", "no_validate"])]
+    pub validate_with: Option<String>,
+
+    /// How long to wait for the --validate-with command (e.g. "5s", plain numbers are
+    /// milliseconds) before killing it and counting it as failed
+    #[arg(long, value_name = "TIME", value_parser = parse_duration, default_value = "5s")]
+    #[arg(requires = "validate_with")]
+    pub validate_timeout: Duration,
+
+    /// With --handshake or --strict-connect, how long to wait for the X server's reply once
+    /// connected, e.g. "200ms" or "1s" (plain numbers are milliseconds). Defaults to the --timeout.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    #[arg(visible_alias = "probe-first-byte-timeout")]
+    pub read_timeout: Option<Duration>,
+
+    /// Keep each probe connection open this long (plain numbers are milliseconds) and only count
+    /// it if the other end hasn't closed it by then, to skip forwarders that accept connections
+    /// and then immediately reset them. A dropped connection is retried like a refused one.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration)]
+    #[arg(conflicts_with_all = ["local", "unix_socket", "no_validate"])]
+    pub require_established_ms: Option<Duration>,
+
+    /// Don't probe for an X server, just print the DISPLAY for the host IP. Useful with --host
+    /// when the setup is known to be static.
+    #[arg(long, conflicts_with_all = ["local", "unix_socket", "parallel", "scan"])]
+    pub no_validate: bool,
+
+    /// Use /etc/resolv.conf to determine the host IP address rather than parsing the output of
+    /// `ip route`. Shorthand for `--method resolv-conf`, and overrides it.
+    #[arg(short = 'R', long)]
+    pub resolv_conf: bool,
+
+    /// Comma-separated list of host IP detection methods to try in order. Later methods are
+    /// fallbacks, used only if the earlier ones fail.
+    #[arg(short, long, value_enum, value_delimiter = ',', default_value = "route")]
+    #[arg(env = "WSL2_DISPLAY_METHOD")]
+    pub method: Vec<Method>,
+
+    /// Routing table to find the default route in, instead of the main table. Use "all" to check
+    /// every table.
+    #[arg(long, value_name = "TABLE")]
+    pub route_table: Option<String>,
+
+    /// With the route method, only use a default route through this network interface, e.g. eth0
+    /// rather than a VPN's tun0
+    #[arg(long, value_name = "NAME", conflicts_with = "route_probe")]
+    pub interface: Option<String>,
+
+    /// With the bridge method, the name of the WSL network interface, where `*` matches anything.
+    /// Its gateway is used even when another interface (like Docker's or a VPN's) has the default
+    /// route.
+    #[arg(long, value_name = "PATTERN", default_value = "eth0")]
+    pub bridge_interface: String,
+
+    /// With the route method, use the gateway that `ip route get` says traffic to this address
+    /// would go through, rather than the default route's. This can differ when policy routing or
+    /// a VPN is in use. Without an address, the route to 1.1.1.1 is used.
+    #[arg(long, visible_alias = "route-to", value_name = "IP", conflicts_with = "route_table")]
+    #[arg(num_args = 0..=1, default_missing_value = "1.1.1.1")]
+    pub route_probe: Option<IpAddr>,
+
+    /// Probe every host IP found by the --method list at once, rather than only the first one
+    /// found, and use whichever connects first
+    #[arg(short, long)]
+    pub parallel: bool,
+
+    /// With --parallel, milliseconds to wait before starting each candidate's probe after the one
+    /// before it, so that earlier methods' candidates are preferred
+    #[arg(long, default_value = "50", value_name = "MS")]
+    #[arg(value_parser = |s: &str| s.parse().map(Duration::from_millis))]
+    pub stagger: Duration,
+
+    /// With --parallel, run every method before probing any candidates, rather than probing each
+    /// candidate as soon as its method finds it. Candidates are then started in method order.
+    #[arg(long, requires = "parallel")]
+    pub wait_all_methods: bool,
+
+    /// With --parallel, race the IPv4 and IPv6 candidates against each other happy-eyeballs style:
+    /// the --lead-family candidates start right away, and the others start after this delay,
+    /// e.g. "250ms" or "1s" (plain numbers are milliseconds). Within each family the candidates
+    /// are still staggered.
+    #[arg(long, value_name = "TIME", value_parser = parse_duration, requires = "parallel")]
+    pub family_delay: Option<Duration>,
+
+    /// With --family-delay, which family's candidates start first
+    #[arg(long, value_name = "FAMILY", default_value = "ipv6")]
+    pub lead_family: IpFamily,
+
+    /// With --parallel, how to choose between the IPv4 and IPv6 hosts that connect. The first
+    /// candidate to connect only wins once the policy can't prefer another, so the result doesn't
+    /// depend on which family happened to answer first. Without a POLICY (given as
+    /// `--dual-stack-policy=POLICY`), prefer-v4.
+    #[arg(long, value_enum, value_name = "POLICY", num_args = 0..=1, require_equals = true)]
+    #[arg(default_value_t = DualStackPolicy::PreferV4, default_missing_value = "prefer-v4")]
+    #[arg(requires = "parallel")]
+    pub dual_stack_policy: DualStackPolicy,
+
+    /// With --parallel, probe every display on every candidate rather than stopping at the first
+    /// to connect, and use whichever connected fastest. This always takes as long as the slowest
+    /// probe, for picking the lowest-latency X server when several are reachable. The latencies
+    /// are shown with --verbose.
+    #[arg(long, requires = "parallel", conflicts_with = "dual_stack_policy")]
+    pub connect_all_then_report_best: bool,
+
+    /// Give up on the host after this many attempts in a row time out without it ever refusing a
+    /// connection. A host that never answers at all is most likely the wrong address rather than
+    /// one whose X server isn't up yet, so there's no point using up the rest of the --retries.
+    #[arg(long, value_name = "N", value_parser = parse_nonzero)]
+    pub probe_timeout_is_fatal_after: Option<u16>,
+
+    /// Stop as soon as the host refuses a connection, without retrying, and exit with status 3.
+    /// This tells "host reachable, X server not up yet" apart from the host not being reachable.
+    #[arg(long)]
+    pub refuse_is_success: bool,
+
+    /// When no X server is found, also try connecting to the host on this port (e.g. 22 or 135),
+    /// to tell an X port blocked by a firewall apart from an unreachable host. Not used with
+    /// --parallel.
+    #[arg(long, value_name = "PORT")]
+    pub sentinel_port: Option<u16>,
+
+    /// Before probing the host, make one throwaway connection to it and ignore how it goes. The
+    /// first connection on a cold network waits for the host's ARP lookup, which can make it
+    /// time out spuriously (e.g. right after boot) or skew the --probe-count latencies. Costs a
+    /// round trip, or up to a --timeout if the host doesn't answer.
+    #[arg(long)]
+    pub warmup: bool,
+
+    /// Set TCP_NODELAY on the probe connection
+    #[arg(long)]
+    pub nodelay: bool,
+
+    /// Bind X probe connections to this local port, for firewalls that only let X traffic out
+    /// from certain source ports. If it's in use, the next few ports are tried.
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..))]
+    pub connect_source_port: Option<u16>,
+
+    /// How many times the kernel resends the SYN of each probe connection before giving up on it
+    /// (TCP_SYNCNT, normally 6), so that a host that drops packets is given up on well before the
+    /// --timeout, leaving time for more --retries. Or use a bigger number to keep trying longer
+    /// within each attempt. Linux only.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=127))]
+    pub tcp_syn_retries: Option<u8>,
+
+    /// How to talk to the X server. With tls, a probe only counts once the TLS handshake is done,
+    /// checking the certificate against the system's CA certificates for the --hostname, or the
+    /// host IP without one. Only available with the tls feature.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_enum, default_value_t = ProbeProtocol::Raw, value_name = "PROTOCOL")]
+    #[arg(conflicts_with_all = ["local", "unix_socket"])]
+    pub probe_protocol: ProbeProtocol,
+
+    /// Look up and print the host's name with reverse DNS. This is only informational, and is
+    /// limited by --timeout.
+    #[arg(long)]
+    pub resolve_name: bool,
+
+    /// Lock file used to serialize detection between concurrent processes.
+    ///
+    /// The first process to take the lock does detection and saves its result in the file, other
+    /// processes wait for it and reuse that result if they were given the same options. If the
+    /// lock can't be taken within a couple seconds, detection runs independently.
+    #[arg(long, value_name = "PATH")]
+    pub lock: Option<PathBuf>,
+
+    /// Remember which detection method found the host in this file, and try it first next time.
+    /// The saved method is ignored once the routing table or /etc/resolv.conf changes.
+    #[arg(long, value_name = "PATH")]
+    pub cache: Option<PathBuf>,
+
+    /// Ignore what's saved in the --cache file and do full detection, then replace the file with
+    /// the method that found the host. For when the network changed in a way that the cache
+    /// didn't notice.
+    #[arg(long, requires = "cache")]
+    pub refresh_cache: bool,
+
+    /// Order the detection methods by how often and how recently each found the host, going by
+    /// the last several runs recorded in the --cache file, rather than just trying the last one
+    /// first. Like the cached method, the history is forgotten when the network changes.
+    #[arg(long, requires = "cache")]
+    pub adaptive_order: bool,
+
+    /// When no X server is found, ask for the host IP on the terminal and probe that, until one
+    /// works or an empty line is entered. With --cache, the IP is saved and used right away by
+    /// later runs on the same network. Never prompts unless stdin and stderr are terminals.
+    #[arg(long, conflicts_with_all = ["host", "probe_only", "watch", "serve", "query"])]
+    pub interactive: bool,
+
+    /// Append a timestamped line recording the result of every run to this file, to help track
+    /// down intermittent failures
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Print the total time taken, for detection and probing, to stderr at the end of the run
+    #[arg(long)]
+    pub timing: bool,
+
+    /// When to color the warning and error labels on stderr
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, value_name = "WHEN")]
+    pub color: ColorChoice,
+
+    /// Don't warn about finding multiple default routes, for setups that legitimately have more
+    /// than one. The first is still used, or use --parallel to probe all of their gateways.
+    #[arg(long)]
+    pub no_route_warning: bool,
+
+    /// Treat warnings, such as finding multiple default routes, as errors rather than carrying on
+    /// with a best guess
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Probe for an X server on this machine's Unix socket rather than on the WSL host over TCP
+    #[arg(short = 'L', long)]
+    pub local: bool,
+
+    /// Probe an X server forwarded to the Unix socket at this path (e.g. by socat or ssh), rather
+    /// than the WSL host over TCP
+    #[arg(long, value_name = "PATH", conflicts_with = "local")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Which Unix socket to use with --local: the filesystem socket /tmp/.X11-unix/X<n>, the
+    /// abstract socket @/tmp/.X11-unix/X<n>, or auto to try abstract then filesystem
+    #[arg(long, value_enum, default_value_t = XSocket::Auto, value_name = "KIND")]
+    pub x_socket: XSocket,
+
+    /// Print the detection methods that would be tried, in order, then exit without running them
+    #[arg(long)]
+    pub print_method: bool,
+
+    /// Print what detection would do as a JSON object, then exit without doing it: where the
+    /// host IP comes from and the methods that would find it, the displays and ports to probe,
+    /// and the timeouts and retries. Can be combined with --json-pretty.
+    #[arg(long)]
+    pub plan: bool,
+
+    /// Print every detection method that can be given to --method, whether it looks like it would
+    /// work here, and what it does, then exit. With --json or --json-pretty, print them as a JSON
+    /// array.
+    #[arg(long)]
+    pub list_methods: bool,
+
+    /// Print every host IP found by the --method list, and which methods found it, without
+    /// probing for an X server
+    #[arg(long)]
+    pub list_candidates: bool,
+
+    /// How to write the IP addresses printed by --list-candidates
+    #[arg(long, value_enum, default_value_t = IpFormat::Dotted, value_name = "FORMAT")]
+    #[arg(requires = "list_candidates")]
+    pub ip_format: IpFormat,
+
+    /// Do detection and a single connection attempt (ignoring --retries), but print nothing. The
+    /// exit status is zero only if an X server was found, e.g. for
+    /// `if wsl2-get-display --probe-only; then ...`
+    #[arg(long, conflicts_with_all = ["json", "json_pretty", "json_verbose", "count_only"])]
+    pub probe_only: bool,
+
+    /// Print the result as a JSON object, including the number of connection attempts made
+    #[arg(long, conflicts_with = "count_only")]
+    pub json: bool,
+
+    /// Print a fatal error as a JSON object on stderr rather than as text, like
+    /// `{"error":"...","kind":"detection","found":false}`. The kind is detection, connect, io, or
+    /// other.
+    #[arg(long)]
+    pub json_errors: bool,
+
+    /// Like --json, but pretty-printed over multiple lines for reading in a terminal
+    #[arg(long, conflicts_with = "count_only")]
+    pub json_pretty: bool,
+
+    /// Like --json, but with an array of every connection attempt made (the host, port, error
+    /// and latency of each) in place of the attempt count. Can be combined with --json-pretty.
+    #[arg(long, conflicts_with = "count_only")]
+    pub json_verbose: bool,
+
+    /// Also check for a PulseAudio server on the host's TCP port 4713, and if one answers print
+    /// its PULSE_SERVER (like "tcp:172.16.0.1:4713") on a second line, or export it too with
+    /// --export and --systemd-env
+    #[arg(long, conflicts_with_all = ["local", "unix_socket"])]
+    pub pulse: bool,
+
+    /// Don't bracket IPv6 addresses in the DISPLAY, e.g. "fd00::1:0" rather than "[fd00::1]:0".
+    /// This isn't standard, it's only a workaround for X clients that can't handle the brackets.
+    #[arg(long, visible_alias = "format-ipv6-no-brackets")]
+    pub ipv6_no_brackets: bool,
+
+    /// Once an X server is found, connect to it N more times and print the minimum, median, and
+    /// maximum connection latency instead of the DISPLAY, e.g. to compare networking modes
+    #[arg(long, value_name = "N", value_parser = parse_nonzero)]
+    #[arg(conflicts_with_all = ["local", "unix_socket", "no_validate", "probe_only", "watch"])]
+    pub probe_count: Option<u16>,
+
+    /// Keep running, redoing detection every TIME (e.g. "5s", plain numbers are milliseconds)
+    /// and printing the output again each time it changes. An empty line means the X server went
+    /// away. Detection also reruns as soon as /etc/resolv.conf changes, which WSL does when the
+    /// host IP changes. Hidden in favor of the watch subcommand, but kept for old scripts.
+    #[arg(long, hide = true, value_name = "TIME", value_parser = parse_duration)]
+    #[arg(conflicts_with_all = ["probe_only", "lock", "apply_systemd", "apply_windows", "write"])]
+    #[arg(conflicts_with = "apply_environment_d")]
+    pub watch: Option<Duration>,
+
+    /// With --watch, also write each change to this FIFO (named pipe), creating it if needed, so
+    /// another process can block reading it. Changes are skipped while nothing is reading. Hidden
+    /// like --watch, it's `watch --fifo` now.
+    #[arg(long, hide = true, value_name = "PATH", requires = "watch")]
+    pub fifo: Option<PathBuf>,
+
+    /// Keep running as a server on the Unix socket at PATH, so that many shells can share one
+    /// detection. Detection runs when the first client connects and its output is kept for later
+    /// clients, which just read until the server hangs up. Nothing is sent when no X server was
+    /// found, and the next client tries again.
+    #[arg(long, value_name = "PATH")]
+    #[arg(conflicts_with_all = ["probe_only", "watch", "probe_count", "apply_systemd", "write"])]
+    #[arg(conflicts_with_all = ["apply_windows", "apply_environment_d"])]
+    pub serve: Option<PathBuf>,
+
+    /// Get the output from the --serve server at PATH rather than doing detection
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["serve", "watch"])]
+    pub query: Option<PathBuf>,
+
+    /// Print a shell command to export DISPLAY rather than just the DISPLAY string, for use like
+    /// `eval "$(wsl2-get-display --export)"`. Nothing is printed if no X server was found.
+    #[arg(long)]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    pub export: bool,
+
+    /// Print DISPLAY=<display> for a systemd EnvironmentFile or `systemctl --user
+    /// set-environment`, rather than just the DISPLAY string
+    #[arg(long)]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    #[arg(conflicts_with = "export")]
+    pub systemd_env: bool,
+
+    /// Print an `env DISPLAY=<display>` prefix to put in front of a command, e.g. in a .desktop
+    /// file's Exec line. With a COMMAND (given as `--exec-form=COMMAND`), print a whole
+    /// `wsl.exe -- env DISPLAY=<display> COMMAND` line for a Windows shortcut instead.
+    #[arg(long, value_name = "COMMAND", num_args = 0..=1, default_missing_value = "")]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    #[arg(conflicts_with_all = ["export", "systemd_env", "emit_function"])]
+    pub exec_form: Option<String>,
+
+    /// Set DISPLAY in the systemd user manager's environment with `systemctl --user
+    /// set-environment`, so that user services started afterwards get it
+    #[arg(long)]
+    pub apply_systemd: bool,
+
+    /// Write DISPLAY=<display> to a systemd environment.d drop-in, so that user sessions started
+    /// afterwards get it. The file is ~/.config/environment.d/wsl-display.conf (or under
+    /// $XDG_CONFIG_HOME), or PATH if given as `--apply-environment-d=PATH`. It's replaced
+    /// atomically, and left alone if it already has the same DISPLAY.
+    #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true)]
+    #[arg(default_missing_value = "", conflicts_with = "probe_only")]
+    // the default parser refuses the empty default_missing_value
+    #[arg(value_parser = |s: &str| Ok::<_, String>(PathBuf::from(s)))]
+    pub apply_environment_d: Option<PathBuf>,
+
+    /// Also set a Windows user environment variable (DISPLAY, or NAME if given as
+    /// `--apply-windows=NAME`) to the display with `setx.exe`, for Windows programs that need to
+    /// reach the same X server. This needs WSL interop, and only affects Windows programs started
+    /// afterwards. setx can take a few seconds, so it is left running in the background after
+    /// --windows-timeout.
+    #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true)]
+    #[arg(default_missing_value = "DISPLAY")]
+    pub apply_windows: Option<String>,
+
+    /// How long to wait for setx.exe with --apply-windows (e.g. "5s", plain numbers are
+    /// milliseconds)
+    #[arg(long, value_name = "TIME", value_parser = parse_duration, default_value = "5s")]
+    #[arg(requires = "apply_windows")]
+    pub windows_timeout: Duration,
+
+    /// When an X server is found, also print this message to stderr for a human to read, e.g.
+    /// "Found X server at {host} on display {display}". {host}, {display}, {method} and
+    /// {attempts} are replaced with the result, missing values with "-".
+    #[arg(long, value_name = "TEMPLATE")]
+    pub message: Option<String>,
+
+    /// Also write the output to this file, replacing it atomically, e.g. to save an --export line
+    /// for shells started later. The file is left alone if there's nothing to print.
+    #[arg(long, value_name = "PATH", conflicts_with = "probe_only")]
+    pub write: Option<PathBuf>,
+
+    /// Also write DISPLAY=<display> (and PULSE_SERVER=<server> with --pulse) lines to this file
+    /// descriptor, inherited from the parent process, so that a program running this one can
+    /// read the result separately from stdout, e.g. `3>&1 >/dev/tty`. Nothing is written if no X
+    /// server was found.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i32).range(3..))]
+    #[arg(conflicts_with_all = ["watch", "serve", "query"])]
+    pub results_fd: Option<i32>,
+
+    /// The shell syntax to use for --export
+    #[arg(long, value_enum, default_value_t = Shell::Sh, value_name = "SHELL")]
+    pub shell: Shell,
+
+    /// Print only the number of connection attempts made, rather than the DISPLAY string
+    #[arg(long)]
+    pub count_only: bool,
+
+    /// Print a short status for a shell prompt rather than the DISPLAY string: the --icon-found
+    /// glyph and the display number like "✓:1" if an X server answered, otherwise the
+    /// --icon-missing glyph, even when detection fails. Implies --fast, so add --cache too to keep
+    /// the prompt quick.
+    #[arg(long, conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose"])]
+    #[arg(conflicts_with_all = ["count_only", "exec_form", "probe_count", "compat"])]
+    pub icon: bool,
+
+    /// The glyph that --icon prints when an X server is found
+    #[arg(long, value_name = "GLYPH", default_value = "✓", requires = "icon")]
+    pub icon_found: String,
+
+    /// The glyph that --icon prints when no X server is found
+    #[arg(long, value_name = "GLYPH", default_value = "✗", requires = "icon")]
+    pub icon_missing: String,
+
+    /// Probe every --scan display rather than stopping at the first X server, print nothing, and
+    /// exit with a mask whose bits say which ones have one: bit 0 (value 1) for the first display
+    /// in the --scan list as given, bit 1 (value 2) for the second, and so on, for at most 8
+    /// displays, e.g. 5 means the first and third displays have an X server. The mask takes the
+    /// whole exit status, so 0 means none were found and 1 to 3 look like the usual statuses. A
+    /// fatal error still exits with 2, check for an error message to tell it from the mask.
+    #[arg(long, requires = "scan")]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    #[arg(conflicts_with_all = ["watch", "serve", "probe_count", "parallel"])]
+    pub bitmask: bool,
+
+    /// Print a full report of the detection run for attaching to bug reports: the result and
+    /// timing of every method, the probe result, and facts about the environment like the
+    /// iproute2 version and WSL networking mode. Hidden in favor of the report subcommand.
+    #[arg(long, hide = true, value_enum, value_name = "FORMAT")]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    pub report: Option<ReportFormat>,
+
+    /// Behave exactly like the original `nc -z -w 0.5` shell script, whatever the defaults become:
+    /// the resolv-conf method, a 500ms timeout, a single attempt, and printing just host:display
+    /// or nothing. Every other option except the display number and --verbose is ignored.
+    #[arg(long)]
+    pub compat: bool,
+
+    /// Do as little as possible, for running in every interactive shell's startup file: the
+    /// proc-route then resolv-conf methods, which just read files rather than running `ip`, a
+    /// single attempt with a timeout of at most 100ms, no --handshake, --strict-connect,
+    /// --require-established-ms or --parallel, and no warnings. These override the options they
+    /// replace, everything else still applies.
+    #[arg(long, conflicts_with = "compat")]
+    pub fast: bool,
+
+    /// Make every connection attempt return this result without touching the network, for
+    /// testing the retry logic and exit codes. Only available with the simulate feature.
+    #[cfg(feature = "simulate")]
+    #[arg(long, hide = true, value_enum, value_name = "RESULT")]
+    pub simulate: Option<Simulate>,
+
+    /// Print version and build information as JSON, then exit
+    #[arg(long)]
+    pub version_json: bool,
+
+    #[command(subcommand)]
+    pub mode: Option<Mode>,
+
+    /// Print the definition of a `wsl2_display` shell function for the --shell, then exit. Calling
+    /// it sets DISPLAY by running this program with the other options given here, the first time
+    /// only, and then runs its arguments as a command. Put `eval "$(wsl2-get-display
+    /// --emit-function --cache ~/.cache/wsl2-display)"` in a shell startup file to only pay for
+    /// detection once something needs X, like `wsl2_display xterm`.
+    #[arg(long)]
+    #[arg(conflicts_with_all = ["probe_only", "json", "json_pretty", "json_verbose", "count_only"])]
+    #[arg(conflicts_with_all = ["systemd_env", "watch", "serve", "query"])]
+    pub emit_function: bool,
+}
+
+impl Args {
+    /// The detection methods to try, accounting for the -R shorthand
+    pub fn methods(&self) -> &[Method] {
+        if self.resolv_conf {
+            &[Method::ResolvConf]
+        } else {
+            &self.method
+        }
+    }
+
+    /// Move `method` to the front of the --method list, if it's in it
+    pub fn try_method_first(&mut self, method: Method) {
+        if let Some(i) = self.method.iter().position(|&m| m == method) {
+            self.method[..=i].rotate_right(1);
+        }
+    }
+
+    /// Move the methods in `ranked` to the front of the --method list in that order, leaving the
+    /// rest in their order after them
+    pub fn try_methods_first(&mut self, ranked: &[Method]) {
+        for &method in ranked.iter().rev() {
+            self.try_method_first(method);
+        }
+    }
+
+    /// Set the options that the subcommand in `mode` is a shortcut for
+    pub fn apply_mode(&mut self) {
+        match &self.mode {
+            Some(Mode::Check) => self.probe_only = true,
+            Some(Mode::Doctor) => self.verbose = self.verbose.max(1),
+            Some(Mode::Watch(watch)) => {
+                self.watch = Some(watch.interval);
+                self.fifo = watch.fifo.clone();
+            }
+            Some(Mode::Report(report)) => self.report = Some(report.format),
+            Some(Mode::Find | Mode::Completions(_)) | None => (),
+        }
+    }
+
+    /// Set the options that --fast is a shortcut for
+    pub fn apply_fast(&mut self) {
+        self.method = vec![Method::ProcRoute, Method::ResolvConf];
+        self.resolv_conf = false;
+        self.timeout = self.timeout.min(FAST_TIMEOUT);
+        self.retries = 1;
+        self.retry_schedule = None;
+        self.handshake = false;
+        self.strict_connect = false;
+        self.require_established_ms = None;
+        self.parallel = false;
+        self.no_route_warning = true;
+    }
+
+    /// Reset everything that --compat pins to the original script's behavior
+    pub fn pin_compat(&mut self) {
+        *self = Args {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            verbose: self.verbose,
+            display_number: self.display_number,
+            resolv_conf: true,
+            compat: true,
+            ..Args::default()
+        };
+    }
+
+    /// How many connection attempts to make for each display: one more than the number of
+    /// --retry-schedule delays if there is one, otherwise --retries
+    pub fn attempts(&self) -> u16 {
+        match &self.retry_schedule {
+            // the length is checked when parsing
+            Some(RetrySchedule(delays)) => u16::try_from(delays.len() + 1).unwrap_or(u16::MAX),
+            None => self.retries,
+        }
+    }
+
+    /// The timeout for reading the --handshake or --strict-connect reply
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout.unwrap_or(self.timeout)
+    }
+
+    /// The display numbers to probe, in order: requested_displays(), then the --port-fuzz
+    /// neighbors of `display_number`. When probing TCP ports, displays whose port would be past
+    /// 65535 are left out, see out_of_range_displays().
+    pub fn displays(&self) -> Vec<u16> {
+        let mut displays = self.all_displays();
+        if self.probes_display_ports() {
+            displays.retain(|&display| DISPLAY_PORT_OFFSET.checked_add(display).is_some());
+        }
+        displays
+    }
+
+    /// The displays that displays() leaves out because their TCP port would be past 65535, e.g.
+    /// --display-number 59000 with --display-offset 1000
+    pub fn out_of_range_displays(&self) -> Vec<u16> {
+        if !self.probes_display_ports() {
+            return Vec::new();
+        }
+        let mut displays = self.all_displays();
+        displays.retain(|&display| DISPLAY_PORT_OFFSET.checked_add(display).is_none());
+        displays
+    }
+
+    /// Whether each display is probed on its own TCP port, rather than a Unix socket or --port
+    fn probes_display_ports(&self) -> bool {
+        !self.local && self.unix_socket.is_none() && self.port.is_none()
+    }
+
+    /// displays(), including any whose TCP port would be out of range
+    fn all_displays(&self) -> Vec<u16> {
+        let mut displays = self.requested_displays();
+        for distance in 1..=self.port_fuzz {
+            let below = self.display_number.checked_sub(distance);
+            let above = self.display_number.checked_add(distance);
+            for display in below.into_iter().chain(above) {
+                if !displays.contains(&display) {
+                    displays.push(display);
+                }
+            }
+        }
+        displays
+    }
+
+    /// The display numbers asked for: the --scan list if given (in --scan-order), otherwise
+    /// `display_number` and then `display_number + display_offset`. Each display is only in the
+    /// list once, at its first position, however many times it was given. The list isn't sorted,
+    /// --scan-order already decides which displays go first.
+    pub fn requested_displays(&self) -> Vec<u16> {
+        let displays: Vec<u16> = match &self.scan {
+            Some(DisplayList(displays)) if self.scan_order == ScanOrder::Given => displays.clone(),
+            Some(DisplayList(displays)) => {
+                let current = env::var("DISPLAY").ok().and_then(|d| parse_display(&d).ok());
+                let likely = current.map(|(_, display, _)| display).into_iter().chain([0, 1]);
+                let requested: HashSet<u16> = displays.iter().copied().collect();
+                let first = likely.filter(|display| requested.contains(display));
+                first.chain(displays.iter().copied()).collect()
+            }
+            None => {
+                let offset = self.display_offset.filter(|&offset| offset != 0);
+                let second = offset.and_then(|offset| self.display_number.checked_add(offset));
+                std::iter::once(self.display_number).chain(second).collect()
+            }
+        };
+        let mut seen = HashSet::with_capacity(displays.len());
+        displays.into_iter().filter(|&display| seen.insert(display)).collect()
+    }
+}
+
+impl Default for Args {
+    /// The same options as running the binary with no arguments and none of the WSL2_DISPLAY_*
+    /// environment variables set
+    fn default() -> Self {
+        Args {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            retry_schedule: None,
+            refused_wait: None,
+            jitter: Duration::ZERO,
+            verbose: 0,
+            attempts_log_level: AttemptLogLevel::All,
+            display_number: 1,
+            screen: None,
+            scan: None,
+            display_offset: None,
+            port_fuzz: 0,
+            scan_order: ScanOrder::Likely,
+            from_xauth: false,
+            budget: None,
+            timeout_per_candidate: None,
+            timeout_fraction: None,
+            prefer: None,
+            hostname: None,
+            dns_server: None,
+            allow_subnet: Vec::new(),
+            allow_loopback: false,
+            require_wsl: false,
+            assume_wsl: false,
+            host: None,
+            host_from_stdin: false,
+            host_file: None,
+            port: None,
+            handshake: false,
+            strict_connect: false,
+            validate_with: None,
+            validate_timeout: Duration::from_secs(5),
+            read_timeout: None,
+            require_established_ms: None,
+            no_validate: false,
+            resolv_conf: false,
+            method: vec![Method::Route],
+            route_table: None,
+            interface: None,
+            bridge_interface: "eth0".to_owned(),
+            route_probe: None,
+            parallel: false,
+            stagger: Duration::from_millis(50),
+            wait_all_methods: false,
+            family_delay: None,
+            lead_family: IpFamily::Ipv6,
+            dual_stack_policy: DualStackPolicy::PreferV4,
+            connect_all_then_report_best: false,
+            probe_timeout_is_fatal_after: None,
+            refuse_is_success: false,
+            sentinel_port: None,
+            warmup: false,
+            nodelay: false,
+            connect_source_port: None,
+            tcp_syn_retries: None,
+            #[cfg(feature = "tls")]
+            probe_protocol: ProbeProtocol::Raw,
+            resolve_name: false,
+            lock: None,
+            cache: None,
+            refresh_cache: false,
+            adaptive_order: false,
+            interactive: false,
+            log_file: None,
+            timing: false,
+            color: ColorChoice::Auto,
+            no_route_warning: false,
+            strict: false,
+            local: false,
+            unix_socket: None,
+            x_socket: XSocket::Auto,
+            print_method: false,
+            plan: false,
+            list_methods: false,
+            list_candidates: false,
+            ip_format: IpFormat::Dotted,
+            probe_only: false,
+            json: false,
+            json_errors: false,
+            json_pretty: false,
+            json_verbose: false,
+            pulse: false,
+            ipv6_no_brackets: false,
+            probe_count: None,
+            watch: None,
+            fifo: None,
+            serve: None,
+            query: None,
+            export: false,
+            systemd_env: false,
+            exec_form: None,
+            apply_systemd: false,
+            apply_environment_d: None,
+            apply_windows: None,
+            windows_timeout: Duration::from_secs(5),
+            message: None,
+            write: None,
+            results_fd: None,
+            shell: Shell::Sh,
+            count_only: false,
+            icon: false,
+            icon_found: "✓".to_owned(),
+            icon_missing: "✗".to_owned(),
+            bitmask: false,
+            report: None,
+            compat: false,
+            fast: false,
+            #[cfg(feature = "simulate")]
+            simulate: None,
+            version_json: false,
+            mode: None,
+            emit_function: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `args` like the binary would, after its name
+    fn parse(args: &[&str]) -> Args {
+        Args::parse_from([env!("CARGO_PKG_NAME")].iter().chain(args))
+    }
+
+    #[test]
+    fn default_matches_no_arguments() {
+        use clap::{CommandFactory, FromArgMatches};
+
+        // parse() without reading the WSL2_DISPLAY_* variables the test may be run with
+        let matches = Args::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from([env!("CARGO_PKG_NAME")])
+            .unwrap();
+        let parsed = Args::from_arg_matches(&matches).unwrap();
+        assert_eq!(format!("{:?}", Args::default()), format!("{parsed:?}"));
+    }
+
+    #[test]
+    fn display_number_with_colon() {
+        assert_eq!(parse_display_number(":1"), Ok(1));
+        assert_eq!(parse_display_number(":1"), parse_display_number("1"));
+        assert_eq!(parse(&[":1"]).display_number, parse(&["1"]).display_number);
+        assert!(parse_display_number("::1").is_err());
+        assert!(parse_display_number(":").is_err());
+    }
+
+    #[test]
+    fn scan_duplicates_probed_once() {
+        let args = parse(&["--scan", "1,0..3,1,2", "--scan-order", "given"]);
+        assert_eq!(args.requested_displays(), [1, 0, 2]);
+        assert_eq!(args.displays(), [1, 0, 2]);
+        // an offset back onto the same display
+        assert_eq!(parse(&["3", "--display-offset", "0"]).requested_displays(), [3]);
+    }
+
+    #[test]
+    fn scan_likely_order() {
+        let mut args = parse(&["--scan", "5,1,3..=7,0,1"]);
+        // without $DISPLAY, 0 and 1 go first and the rest keep their order
+        args.scan_order = ScanOrder::Likely;
+        if env::var_os("DISPLAY").is_none() {
+            assert_eq!(args.requested_displays(), [0, 1, 5, 3, 4, 6, 7]);
+        }
+        let wide = parse(&["--scan", "0..65535,0..65535"]).requested_displays();
+        assert_eq!(wide.len(), 65535);
+    }
+
+    #[test]
+    fn counts_reject_zero() {
+        for flag in ["--retries", "--probe-count", "--probe-timeout-is-fatal-after"] {
+            let e =
+                Args::try_parse_from([env!("CARGO_PKG_NAME"), flag, "0"]).unwrap_err().to_string();
+            assert!(e.contains(&format!("for '{flag} <")), "{e}");
+            assert!(e.contains("must be at least 1"), "{e}");
+        }
+        assert_eq!(parse(&["--probe-count", "1"]).probe_count, Some(1));
+    }
+
+    #[test]
+    fn displays_past_port_range_skipped() {
+        let args = parse(&["59000", "--display-offset", "1000"]);
+        assert_eq!(args.requested_displays(), [59000, 60000]);
+        assert_eq!(args.displays(), [59000]);
+        assert_eq!(args.out_of_range_displays(), [60000]);
+
+        // 6000 + 59535 is the last port
+        let args = parse(&["--scan", "59534..59537", "--scan-order", "given"]);
+        assert_eq!(args.displays(), [59534, 59535]);
+        assert_eq!(args.out_of_range_displays(), [59536]);
+
+        // with a fixed --port the display number doesn't pick the port
+        let args = parse(&["59000", "--display-offset", "1000", "--port", "6000"]);
+        assert_eq!(args.displays(), [59000, 60000]);
+        assert!(args.out_of_range_displays().is_empty());
+    }
+}
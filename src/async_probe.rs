@@ -0,0 +1,316 @@
+//! Async variant of find_display(), using tokio
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use socket2::SockRef;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::{task, time};
+
+use crate::detect::{check_allowed_subnet, detect_host_ips_logged, read_host_file};
+use crate::handshake::x_handshake;
+use crate::probe::{
+    allowed_candidates, attempt_count, attempt_timeout, connect_local_x, connect_unix,
+    find_display_hosts_with, host_socket_addr, probe_socket, report_reverse_lookup,
+    report_sentinel, retry_probe_with, scan_displays_with, time_left, tune_probe_socket,
+    verifies_probes, verify_probe, warm_up, warn_out_of_range,
+};
+use crate::{
+    find_display_parallel, format_display, format_display_brackets, format_pulse_server,
+    format_socket_display, Args, Outcome, PULSE_PORT,
+};
+
+/// A boxed future that can be sent between threads
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Like find_display(), but probes using tokio rather than blocking the current thread.
+///
+/// Host IP detection (reading a file or running `ip`) happens on tokio's blocking thread pool.
+pub async fn find_display_async(args: &Args) -> Result<Outcome> {
+    warn_out_of_range(args)?;
+    find_display_async_inner(args).await
+}
+
+/// find_display_async(), without warning about out of range displays again for every host
+async fn find_display_async_inner(args: &Args) -> Result<Outcome> {
+    if args.parallel {
+        // the race probes each candidate in its own thread anyway
+        let args = args.clone();
+        return task::spawn_blocking(move || find_display_parallel(&args))
+            .await
+            .context("parallel probing task failed")?;
+    }
+
+    if let (Some(path), None) = (&args.host_file, args.host) {
+        let path = path.clone();
+        let ips = task::spawn_blocking(move || read_host_file(&path))
+            .await
+            .context("host file reading task failed")??;
+        return find_display_hosts_async(args, &ips).await;
+    }
+
+    let deadline = args.budget.map(|budget| Instant::now() + budget);
+
+    if args.local {
+        // connecting a Unix socket doesn't block, so there's no need for tokio's version
+        let connect = |display, _timeout| async move { connect_local_x(display, args.x_socket) };
+        let (found, attempt_log) =
+            scan_displays_with(args, deadline, None, connect, time::sleep).await?;
+        return Ok(Outcome {
+            display: found.map(|display| format_display(None, display, args.screen)),
+            attempts: attempt_count(&attempt_log),
+            attempt_log,
+            ..Outcome::default()
+        });
+    }
+
+    if let Some(path) = &args.unix_socket {
+        // like --local, this only blocks for as long as the peer takes to accept
+        debug!("probing Unix socket {}", path.display());
+        let probe = |timeout| async move { connect_unix(path, timeout) };
+        let (found, attempt_log) =
+            retry_probe_with(args, deadline, None, args.display_number, probe, time::sleep).await?;
+        return Ok(Outcome {
+            display: found.then(|| format_socket_display(path, args.display_number, args.screen)),
+            attempts: attempt_count(&attempt_log),
+            attempt_log,
+            ..Outcome::default()
+        });
+    }
+
+    let (host_ips, method, method_log) = match args.host {
+        Some(ip) => (vec![ip], None, Vec::new()),
+        None => {
+            let detect_args = args.clone();
+            let (ips, method, method_log) = task::spawn_blocking(move || {
+                let mut method_log = Vec::new();
+                detect_host_ips_logged(&detect_args, &mut method_log)
+                    .map(|(ips, method)| (ips, method, method_log))
+            })
+            .await
+            .context("host IP detection task failed")??;
+            (ips, Some(method), method_log)
+        }
+    };
+    let host_ip = host_ips[0];
+
+    check_allowed_subnet(args, host_ip)?;
+
+    // like --parallel, every nameserver gets a turn, not just the first
+    let ips = allowed_candidates(args, host_ips);
+    if ips.len() > 1 && !args.no_validate {
+        debug!("trying each of the nameservers {:?}", ips);
+        let outcome = find_display_hosts_async(args, &ips).await?;
+        return Ok(Outcome { method, method_log, ..outcome });
+    }
+
+    if args.no_validate {
+        debug!("not probing {} because of --no-validate", host_ip);
+        return Ok(Outcome {
+            display: Some(format_display_brackets(
+                Some(host_ip),
+                args.display_number,
+                args.screen,
+                !args.ipv6_no_brackets,
+            )),
+            host: Some(host_ip),
+            method,
+            method_log,
+            ..Outcome::default()
+        });
+    }
+
+    if args.warmup {
+        let (warmup_args, timeout) = (args.clone(), attempt_timeout(args, time_left(deadline)));
+        task::spawn_blocking(move || warm_up(&warmup_args, host_ip, timeout))
+            .await
+            .context("warm-up task failed")?;
+    }
+
+    // the lookup can be slow, so run it in the background while we probe
+    let name_lookup = args.resolve_name.then(|| {
+        task::spawn_blocking(move || dns_lookup::lookup_addr(&host_ip).map_err(io::Error::from))
+    });
+
+    let connect = |display, timeout| async move {
+        let sa = host_socket_addr(args, host_ip, display).map_err(io::Error::other)?;
+        attempt_debug!("connecting to {}", sa);
+        let stream = time::timeout(timeout, connect_x(args, sa))
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))?;
+        tune_probe_socket(SockRef::from(&stream), args.nodelay)?;
+        if !verifies_probes(args) {
+            return Ok(stream);
+        }
+        // the handshake is blocking, so do it on the blocking thread pool
+        let mut stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let args = args.clone();
+        let stream =
+            task::spawn_blocking(move || verify_probe(&args, &mut stream).map(|()| stream))
+                .await
+                .map_err(io::Error::other)??;
+        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    };
+    let (found, attempt_log) =
+        scan_displays_with(args, deadline, Some(host_ip), connect, time::sleep).await?;
+
+    if let Some(name_lookup) = name_lookup {
+        let name = match time::timeout(args.timeout, name_lookup).await {
+            Ok(Ok(name)) => name,
+            Ok(Err(e)) => Err(io::Error::other(e)),
+            Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "timed out")),
+        };
+        report_reverse_lookup(host_ip, name);
+    }
+
+    let host_reachable = match args.sentinel_port {
+        Some(port) if found.is_none() => {
+            let sa = SocketAddr::new(host_ip, port);
+            debug!("probing sentinel port {}", sa);
+            let result = time::timeout(args.timeout, TcpStream::connect(sa))
+                .await
+                .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+            Some(report_sentinel(sa, result))
+        }
+        _ => None,
+    };
+
+    let server = match found {
+        Some(display) if args.handshake => {
+            let sa = host_socket_addr(args, host_ip, display)?;
+            let args = args.clone();
+            Some(task::spawn_blocking(move || x_handshake(&args, &sa)).await??)
+        }
+        _ => None,
+    };
+
+    let pulse_server = if args.pulse {
+        let sa = SocketAddr::new(host_ip, PULSE_PORT);
+        debug!("probing PulseAudio server {}", sa);
+        let result = time::timeout(args.timeout, TcpStream::connect(sa))
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+        match result {
+            Ok(_) => Some(format_pulse_server(host_ip, PULSE_PORT)),
+            Err(e) => {
+                debug!("no PulseAudio server at {}: {}", sa, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(Outcome {
+        display: found.map(|display| {
+            format_display_brackets(Some(host_ip), display, args.screen, !args.ipv6_no_brackets)
+        }),
+        host: Some(host_ip),
+        method,
+        method_log,
+        attempts: attempt_count(&attempt_log),
+        attempt_log,
+        host_reachable,
+        server,
+        pulse_server,
+        ..Outcome::default()
+    })
+}
+
+/// Async version of connecting to a TCP X server, with the socket set up by probe_socket() if
+/// needed
+async fn connect_x(args: &Args, sa: SocketAddr) -> io::Result<TcpStream> {
+    match probe_socket(args, &sa)? {
+        Some(sock) => {
+            sock.set_nonblocking(true)?;
+            TcpSocket::from_std_stream(sock.into()).connect(sa).await
+        }
+        None => TcpStream::connect(sa).await,
+    }
+}
+
+/// find_display_hosts(), probing each host with find_display_async()
+async fn find_display_hosts_async(args: &Args, ips: &[IpAddr]) -> Result<Outcome> {
+    find_display_hosts_with(args, ips, find_display_host_async).await
+}
+
+/// Probe the host in `args` for find_display_hosts_async(), boxed because they call each other
+fn find_display_host_async(args: Args) -> BoxFuture<Result<Outcome>> {
+    Box::pin(async move { find_display_async_inner(&args).await })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::process;
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::find_display;
+
+    /// Args probing `port` on localhost quickly
+    fn localhost(port: u16) -> Args {
+        Args {
+            host: Some("127.0.0.1".parse().unwrap()),
+            port: Some(port),
+            allow_loopback: true,
+            assume_wsl: true,
+            timeout: Duration::from_millis(200),
+            refused_wait: Some(Duration::ZERO),
+            ..Args::default()
+        }
+    }
+
+    /// Run find_display_async() to completion
+    fn block_on(args: &Args) -> Result<Outcome> {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(find_display_async(args))
+    }
+
+    #[test]
+    fn future_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&find_display_async(&Args::default()));
+    }
+
+    #[test]
+    fn same_as_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for parallel in [false, true] {
+            let args = Args { parallel, ..localhost(port) };
+            let outcome = block_on(&args).unwrap();
+            assert_eq!(outcome.display.as_deref(), Some("127.0.0.1:1"), "parallel {parallel}");
+            assert_eq!(outcome.display, find_display(&args).unwrap().display);
+        }
+        drop(listener);
+        let outcome = block_on(&Args { retries: 2, ..localhost(port) }).unwrap();
+        assert_eq!(outcome.display, None);
+        assert_eq!(outcome.attempts, 2);
+    }
+
+    #[test]
+    fn host_file() {
+        let listener = TcpListener::bind("127.0.0.2:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = env::temp_dir().join(format!("wsl2-get-display-{}-hosts", process::id()));
+        fs::write(&path, "127.0.0.1\n127.0.0.2\n").unwrap();
+        let args = Args { host: None, host_file: Some(path.clone()), ..localhost(port) };
+        let outcome = block_on(&args);
+        fs::remove_file(path).unwrap();
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.display.as_deref(), Some("127.0.0.2:1"));
+        assert_eq!(outcome.host, Some("127.0.0.2".parse().unwrap()));
+        assert_eq!(outcome.attempts, 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! The --cache file, remembering which detection method worked last time (and the few times before
+//! that, for --adaptive-order), or the host IP given with --interactive
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::Method;
+
+/// Files whose contents change when the network setup does, which makes a cached method stale
+const FINGERPRINT_FILES: &[&str] = &["/etc/resolv.conf", "/proc/net/route"];
+
+/// How many of the most recent successful methods the cache file keeps for --adaptive-order
+const HISTORY_LEN: usize = 8;
+
+/// A hash of the current network setup, from the routing table and resolv.conf. It only has to
+/// match itself between runs on the same system, a missing file just hashes as empty.
+pub fn network_fingerprint() -> String {
+    let mut hasher = DefaultHasher::new();
+    for path in FINGERPRINT_FILES {
+        fs::read(path).unwrap_or_default().hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// What a cache file remembers
+#[derive(Default)]
+struct CacheEntry {
+    method: Option<Method>,
+    host: Option<IpAddr>,
+    /// The methods that found the host on recent runs, most recent first
+    history: Vec<Method>,
+}
+
+/// Read the cache file at `path`, which has nothing in it if it's missing, invalid, or the network
+/// fingerprint no longer matches
+fn read_cache(path: &Path) -> CacheEntry {
+    let Ok(contents) = fs::read_to_string(path)
+        .map_err(|e| debug!("can't read cache file {}: {}", path.display(), e))
+    else {
+        return CacheEntry::default();
+    };
+    let mut fingerprint = None;
+    let mut entry = CacheEntry::default();
+    for field in contents.split_whitespace() {
+        match field.split_once('=') {
+            Some(("fingerprint", value)) => fingerprint = Some(value),
+            Some(("method", value)) => entry.method = Method::from_str(value, false).ok(),
+            Some(("host", value)) => entry.host = value.parse().ok(),
+            Some(("history", value)) => {
+                let methods = value.split(',').filter_map(|m| Method::from_str(m, false).ok());
+                entry.history = methods.take(HISTORY_LEN).collect();
+            }
+            _ => debug!("ignoring unknown cache file field {:?}", field),
+        }
+    }
+    if fingerprint != Some(&network_fingerprint()) {
+        debug!("network changed since cache file {} was written, ignoring it", path.display());
+        return CacheEntry::default();
+    }
+    entry
+}
+
+/// Write `entry` and the current network fingerprint to the cache file at `path`
+fn write_cache(path: &Path, entry: &CacheEntry) -> Result<()> {
+    let mut contents = format!("fingerprint={}", network_fingerprint());
+    if let Some(method) = entry.method {
+        contents += &format!(" method={}", method.name());
+    }
+    if let Some(host) = entry.host {
+        contents += &format!(" host={host}");
+    }
+    if !entry.history.is_empty() {
+        let names: Vec<&str> = entry.history.iter().map(|m| m.name()).collect();
+        contents += &format!(" history={}", names.join(","));
+    }
+    fs::write(path, contents + "\n")
+        .with_context(|| format!("failed to write cache file {}", path.display()))
+}
+
+/// Get the method saved in the cache file at `path`, if there is one and the network fingerprint
+/// still matches. A missing or invalid cache file has no method.
+pub fn cached_method(path: &Path) -> Option<Method> {
+    read_cache(path).method
+}
+
+/// Get the methods in the cache file at `path`'s history, best first, for --adaptive-order. Each
+/// time a method found the host counts for more the more recent it was, so a method that's been
+/// winning lately overtakes one that used to. Empty if there's no history or the network changed.
+pub fn cached_method_order(path: &Path) -> Vec<Method> {
+    let mut scores: Vec<(Method, usize)> = Vec::new();
+    for (age, &method) in read_cache(path).history.iter().enumerate() {
+        let weight = HISTORY_LEN - age;
+        match scores.iter_mut().find(|(m, _)| *m == method) {
+            Some((_, score)) => *score += weight,
+            None => scores.push((method, weight)),
+        }
+    }
+    // stable, so ties go to whichever won most recently
+    scores.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    scores.into_iter().map(|(method, _)| method).collect()
+}
+
+/// Get the host IP that --interactive saved in the cache file at `path`, like cached_method()
+pub fn cached_host(path: &Path) -> Option<IpAddr> {
+    read_cache(path).host
+}
+
+/// Save `method` and the current network fingerprint in the cache file at `path`, adding it to
+/// the history and keeping a saved host IP if they're still valid
+pub fn save_cached_method(path: &Path, method: Method) -> Result<()> {
+    let mut entry = read_cache(path);
+    entry.history.insert(0, method);
+    entry.history.truncate(HISTORY_LEN);
+    write_cache(path, &CacheEntry { method: Some(method), ..entry })
+}
+
+/// Replace the cache file at `path` with just `method` and the current network fingerprint, for
+/// --refresh-cache, forgetting whatever else was saved
+pub fn reset_cached_method(path: &Path, method: Method) -> Result<()> {
+    write_cache(path, &CacheEntry { method: Some(method), host: None, history: vec![method] })
+}
+
+/// Save the `host` IP entered with --interactive in the cache file at `path`
+pub fn save_cached_host(path: &Path, host: IpAddr) -> Result<()> {
+    write_cache(path, &CacheEntry { host: Some(host), ..read_cache(path) })
+}
@@ -0,0 +1,1249 @@
+//! Host IP address detection methods
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde_json::{self, json, Value};
+
+use crate::dns::resolve_with;
+use crate::{Args, IpFamily, Method, Subnet};
+
+/// Determine the host IP using the methods selected in `args`, falling back to each method in turn
+/// when the ones before it fail. Returns the IP and the method that found it.
+pub fn detect_host_ip(args: &Args) -> Result<(IpAddr, Method)> {
+    detect_host_ip_logged(args, &mut Vec::new())
+}
+
+/// detect_host_ip(), also adding each method that was actually run and how it went to `log`
+pub(crate) fn detect_host_ip_logged(
+    args: &Args,
+    log: &mut Vec<MethodAttempt>,
+) -> Result<(IpAddr, Method)> {
+    detect_host_ips_logged(args, log).map(|(ips, method)| (ips[0], method))
+}
+
+/// detect_host_ip_logged(), getting every host IP the method found rather than just the first,
+/// like all of the nameservers in /etc/resolv.conf. The list is never empty.
+pub(crate) fn detect_host_ips_logged(
+    args: &Args,
+    log: &mut Vec<MethodAttempt>,
+) -> Result<(Vec<IpAddr>, Method)> {
+    check_wsl(args)?;
+    let methods = detection_methods(args);
+    let (ips, i) = Detector::builtin(args, &methods)
+        .run(&mut |i, result| {
+            log.push(MethodAttempt {
+                method: methods[i],
+                ip: result.as_ref().ok().map(|ips| ips[0]),
+                error: result.as_ref().err().map(|e| format!("{e:#}")),
+            })
+        })
+        .map_err(DetectionError)?;
+    Ok((ips, methods[i]))
+}
+
+/// A detection method that was run, and what it found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodAttempt {
+    /// The method that was run
+    pub method: Method,
+    /// The host IP it found, None if it failed
+    pub ip: Option<IpAddr>,
+    /// Why it failed, None if it found an IP
+    pub error: Option<String>,
+}
+
+impl MethodAttempt {
+    /// Get the method attempt as a JSON object for --json output
+    pub fn to_json(&self) -> Value {
+        json!({
+            "method": self.method.name(),
+            "ip": self.ip.map(|ip| ip.to_string()),
+            "error": self.error,
+        })
+    }
+}
+
+impl fmt::Display for MethodAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.ip, &self.error) {
+            (Some(ip), _) => write!(f, "{} found {}", self.method.name(), ip),
+            (None, Some(error)) => write!(f, "{} failed ({})", self.method.name(), error),
+            (None, None) => write!(f, "{} failed", self.method.name()),
+        }
+    }
+}
+
+/// The host IP couldn't be found, or isn't one that may be used. It reads the same as the error
+/// it wraps, this only marks what kind of failure it is, for --json-errors.
+#[derive(Debug)]
+pub struct DetectionError(pub anyhow::Error);
+
+impl fmt::Display for DetectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for DetectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// A detection method: something that finds the host IP or says why it couldn't
+pub type DetectFn<'a> = Box<dyn Fn() -> Result<IpAddr> + 'a>;
+
+/// A detection method as a Detector runs it, finding one or more host IPs, best first
+type CandidatesFn<'a> = Box<dyn Fn() -> Result<Vec<IpAddr>> + 'a>;
+
+/// A chain of named host IP detection methods, tried in the order they were registered until one
+/// of them finds an IP.
+///
+/// [`Detector::new`] has the built-in methods selected in `args`, which is what the binary uses.
+/// Embedders can add their own with [`Detector::register_method`] and pass the IP found on to
+/// [`find_display`](crate::find_display) as `--host`. Custom methods are trusted as-is, without
+/// the loopback checks the built-in ones get.
+pub struct Detector<'a> {
+    methods: Vec<(String, CandidatesFn<'a>)>,
+}
+
+impl<'a> Detector<'a> {
+    /// A detector with the built-in methods selected in `args`
+    pub fn new(args: &'a Args) -> Self {
+        Detector::builtin(args, &detection_methods(args))
+    }
+
+    /// A detector with no methods at all
+    pub fn empty() -> Self {
+        Detector { methods: Vec::new() }
+    }
+
+    fn builtin(args: &'a Args, methods: &[Method]) -> Self {
+        let mut detector = Detector::empty();
+        for &method in methods {
+            detector.register(method.name(), Box::new(move || host_ips_from_method(args, method)));
+        }
+        detector
+    }
+
+    /// Add a method to the end of the chain, replacing any method already registered as `name`
+    pub fn register_method(&mut self, name: impl Into<String>, method: DetectFn<'a>) -> &mut Self {
+        self.register(name.into(), Box::new(move || method().map(|ip| vec![ip])))
+    }
+
+    /// register_method(), for a method that finds several host IPs
+    fn register(&mut self, name: impl Into<String>, method: CandidatesFn<'a>) -> &mut Self {
+        let name = name.into();
+        match self.methods.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = method,
+            None => self.methods.push((name, method)),
+        }
+        self
+    }
+
+    /// The names of the registered methods, in the order they're tried
+    pub fn method_names(&self) -> impl Iterator<Item = &str> {
+        self.methods.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Try each method in turn, returning the host IP and the name of the method that found it.
+    /// If every method fails, the error is the last one's.
+    pub fn detect(&self) -> Result<(IpAddr, &str)> {
+        let (ips, i) = self.run(&mut |_, _| ())?;
+        Ok((ips[0], &self.methods[i].0))
+    }
+
+    /// Try each method in turn, passing the index and result of each one run to `record`. The IPs
+    /// found are never empty.
+    fn run(
+        &self,
+        record: &mut dyn FnMut(usize, &Result<Vec<IpAddr>>),
+    ) -> Result<(Vec<IpAddr>, usize)> {
+        for (i, (name, method)) in self.methods.iter().enumerate() {
+            let result = method().and_then(|ips| {
+                ensure!(!ips.is_empty(), "found no host IP");
+                Ok(ips)
+            });
+            record(i, &result);
+            match (result, self.methods.get(i + 1)) {
+                (Ok(ips), _) => {
+                    debug!("{} method found host IP {}", name, ips[0]);
+                    return Ok((ips, i));
+                }
+                (Err(e), Some((next, _))) => {
+                    // a file we can't read is likely a locked down system rather than a transient
+                    // failure, so make some noise about it even though we have a fallback
+                    if is_permission_denied(&e) {
+                        warning!("{:#}, falling back to the {} method", e, next);
+                    } else {
+                        debug!("{} method failed: {:#}, trying {}", name, e, next);
+                    }
+                }
+                (Err(e), None) => return Err(e),
+            }
+        }
+        bail!("no host IP detection methods given")
+    }
+}
+
+/// Determine the host IP using a single detection method, with its options from `args`
+pub(crate) fn host_ip_from_method(args: &Args, method: Method) -> Result<IpAddr> {
+    host_ips_from_method(args, method).map(|ips| ips[0])
+}
+
+/// host_ip_from_method(), also getting the other nameservers in /etc/resolv.conf with the
+/// resolv-conf method, best first. The list is never empty.
+pub(crate) fn host_ips_from_method(args: &Args, method: Method) -> Result<Vec<IpAddr>> {
+    let ips = match method {
+        Method::Route => match args.route_probe {
+            Some(dst) => host_ip_from_route_get(dst),
+            None => route_gateway(
+                args.route_table.as_deref(),
+                args.interface.as_deref(),
+                !args.no_route_warning,
+            ),
+        }
+        .map(|ip| vec![ip]),
+        Method::ResolvConf => host_ips_from_resolv_conf(args.prefer),
+        Method::Hostname => host_ips_from_hostname(args).map(|ips| vec![ips[0]]),
+        Method::Bridge => host_ip_from_bridge(&args.bridge_interface).map(|ip| vec![ip]),
+        Method::ProcRoute => host_ip_from_proc_route().map(|ip| vec![ip]),
+    }?;
+    if let Some(ip) = ips.iter().find(|&&ip| !loopback_allowed(args, ip)) {
+        bail!(
+            "found loopback address {ip}, which can't be the WSL host outside of mirrored \
+             networking mode (is it a local stub resolver?), use --allow-loopback if it really is"
+        );
+    }
+    Ok(ips)
+}
+
+/// Check that `ip` is in one of the --allow-subnet subnets, if any were given
+pub(crate) fn check_allowed_subnet(args: &Args, ip: IpAddr) -> Result<()> {
+    if args.allow_subnet.is_empty() || args.allow_subnet.iter().any(|net| net.contains(ip)) {
+        return Ok(());
+    }
+    let subnets: Vec<String> = args.allow_subnet.iter().map(Subnet::to_string).collect();
+    let e = anyhow!("host IP {ip} is not in an allowed subnet ({})", subnets.join(", "));
+    Err(DetectionError(e).into())
+}
+
+/// Whether `ip` is acceptable as a host IP: anything but loopback, unless --allow-loopback was
+/// given or WSL is using mirrored networking, where the host really is reachable over loopback
+fn loopback_allowed(args: &Args, ip: IpAddr) -> bool {
+    !ip.is_loopback() || args.allow_loopback || mirrored_networking()
+}
+
+/// Check whether this is running in WSL, from the kernel release (like
+/// `5.15.153.1-microsoft-standard-WSL2`) or the variables WSL sets in every process
+pub fn running_in_wsl() -> bool {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let release = release.to_ascii_lowercase();
+    release.contains("microsoft")
+        || release.contains("wsl")
+        || env::var_os("WSL_DISTRO_NAME").is_some()
+        || env::var_os("WSL_INTEROP").is_some()
+}
+
+/// Complain about detecting the host IP outside of WSL, where it's some other machine entirely.
+/// It's a warning, or an error with --require-wsl, and --assume-wsl skips the check.
+fn check_wsl(args: &Args) -> Result<()> {
+    if args.assume_wsl || running_in_wsl() {
+        return Ok(());
+    }
+    let message = "not running in WSL, this tool is intended for WSL2 and the host it finds \
+                   probably isn't a Windows machine (use --assume-wsl to skip this check)";
+    if args.require_wsl {
+        return Err(DetectionError(anyhow!(message)).into());
+    }
+    warning!("{}", message);
+    Ok(())
+}
+
+/// Check whether WSL is using mirrored networking mode, which adds an interface for the host's
+/// loopback
+pub(crate) fn mirrored_networking() -> bool {
+    Path::new("/sys/class/net/loopback0").exists()
+}
+
+/// A possible host IP address and the detection methods that found it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub ip: IpAddr,
+    pub sources: Vec<Method>,
+}
+
+/// Run every detection method selected in `args` and collect all the host IPs they find, without
+/// any fallback logic. Methods that fail are warned about and skipped. With `--host`, that is the
+/// only candidate.
+pub fn host_candidates(args: &Args) -> Result<Vec<Candidate>> {
+    if let Some(ip) = args.host {
+        check_allowed_subnet(args, ip)?;
+        return Ok(vec![Candidate { ip, sources: Vec::new() }]);
+    }
+    if let Some(path) = &args.host_file {
+        // like probing them in turn, hosts outside --allow-subnet are skipped, and it's only an
+        // error when that leaves none
+        let ips = read_host_file(path)?;
+        let mut allowed = Vec::new();
+        let mut rejected = None;
+        for ip in ips {
+            match check_allowed_subnet(args, ip) {
+                Ok(()) => allowed.push(Candidate { ip, sources: Vec::new() }),
+                Err(e) => {
+                    debug!("skipping {} from the host file: {:#}", ip, e);
+                    rejected.get_or_insert(e);
+                }
+            }
+        }
+        return match (allowed.is_empty(), rejected) {
+            (true, Some(e)) => Err(e),
+            _ => Ok(allowed),
+        };
+    }
+    check_wsl(args)?;
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for method in detection_methods(args) {
+        for ip in method_candidate_ips(args, method)? {
+            match candidates.iter_mut().find(|c| c.ip == ip) {
+                Some(candidate) if candidate.sources.contains(&method) => (),
+                Some(candidate) => candidate.sources.push(method),
+                None => candidates.push(Candidate { ip, sources: vec![method] }),
+            }
+        }
+    }
+    ensure!(!candidates.is_empty(), "no host IP candidates found");
+    Ok(candidates)
+}
+
+/// Read the host IPs listed in a --host-file, in order
+pub fn read_host_file(path: &Path) -> Result<Vec<IpAddr>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read host file {}", path.display()))?;
+    let ips = parse_host_file(&contents)?;
+    ensure!(!ips.is_empty(), "no host IP addresses in {}", path.display());
+    Ok(ips)
+}
+
+/// Parse the contents of a --host-file: one IP address per line, skipping blank lines and `#`
+/// comments. Lines that aren't an IP address are warned about and skipped too, and each address
+/// is only listed once.
+pub fn parse_host_file(contents: &str) -> Result<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(ip) if !ips.contains(&ip) => ips.push(ip),
+            Ok(_) => (),
+            Err(e) => {
+                warning!("host file line {}: '{}' isn't an IP address: {}", i + 1, line, e);
+            }
+        }
+    }
+    Ok(ips)
+}
+
+/// Get every host IP that `method` finds, leaving out loopback and --allow-subnet rejects. A
+/// failing method is warned about and finds nothing.
+pub(crate) fn method_candidate_ips(args: &Args, method: Method) -> Result<Vec<IpAddr>> {
+    let ips = match method {
+        Method::Route => match args.route_probe {
+            Some(dst) => host_ip_from_route_get(dst).map(|ip| vec![ip]),
+            None => route_gateways(args.route_table.as_deref(), args.interface.as_deref())
+                .map(|(ips, _)| ips),
+        },
+        Method::ResolvConf => host_ips_from_resolv_conf(args.prefer),
+        Method::Hostname => host_ips_from_hostname(args),
+        Method::Bridge => host_ip_from_bridge(&args.bridge_interface).map(|ip| vec![ip]),
+        Method::ProcRoute => host_ip_from_proc_route().map(|ip| vec![ip]),
+    };
+    let ips = match ips {
+        Ok(ips) => ips,
+        Err(e) => {
+            warning!("{} method failed: {:#}", method.name(), e);
+            return Ok(Vec::new());
+        }
+    };
+    let mut allowed = Vec::new();
+    for ip in ips {
+        if !loopback_allowed(args, ip) {
+            warning!("ignoring loopback address {} from the {} method", ip, method.name());
+            continue;
+        }
+        if let Err(e) = check_allowed_subnet(args, ip) {
+            warning!("ignoring {} from the {} method: {:#}", ip, method.name(), e);
+            continue;
+        }
+        allowed.push(ip);
+    }
+    Ok(allowed)
+}
+
+/// The nameserver WSL puts in /etc/resolv.conf when DNS tunneling is enabled. It's a proxy inside
+/// the VM rather than the host, so the resolv-conf method can't be used.
+const DNS_TUNNELING_NAMESERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 255, 255, 254));
+
+/// Whether `method` could work here, as far as can be told without running it: the route and
+/// bridge methods need `ip` on the PATH, the file-based ones need their file, and the hostname
+/// method needs --hostname
+pub fn method_available(args: &Args, method: Method) -> bool {
+    let ip_on_path = || {
+        env::split_paths(&env::var_os("PATH").unwrap_or_default())
+            .any(|dir| dir.join("ip").is_file())
+    };
+    match method {
+        Method::Route => ip_on_path(),
+        Method::ResolvConf => Path::new("/etc/resolv.conf").exists(),
+        Method::Hostname => args.hostname.is_some(),
+        Method::Bridge => ip_on_path() && Path::new("/sys/class/net").is_dir(),
+        Method::ProcRoute => Path::new("/proc/net/route").exists(),
+    }
+}
+
+/// The methods from `args` to actually use. With DNS tunneling the resolv.conf nameserver isn't
+/// the host, so that method is replaced by the route method.
+pub(crate) fn detection_methods(args: &Args) -> Vec<Method> {
+    let methods = args.methods();
+    if !methods.contains(&Method::ResolvConf) || !dns_tunneling_enabled() {
+        return methods.to_vec();
+    }
+    debug!("WSL DNS tunneling is enabled, using the route method rather than resolv-conf");
+    let mut methods: Vec<Method> =
+        methods.iter().copied().filter(|&m| m != Method::ResolvConf).collect();
+    if !methods.contains(&Method::Route) && !methods.contains(&Method::ProcRoute) {
+        methods.push(Method::Route);
+    }
+    methods
+}
+
+/// Check for WSL DNS tunneling, either from the tunneling nameserver in /etc/resolv.conf or a
+/// `dnsTunneling=true` setting in /etc/wsl.conf
+pub(crate) fn dns_tunneling_enabled() -> bool {
+    if let Ok(contents) = fs::read_to_string("/etc/resolv.conf") {
+        if parse_resolv_conf(&contents).is_ok_and(|ip| ip == DNS_TUNNELING_NAMESERVER) {
+            return true;
+        }
+    }
+    fs::read_to_string("/etc/wsl.conf").is_ok_and(|contents| {
+        contents.lines().filter_map(|line| line.split_once('=')).any(|(key, value)| {
+            key.trim().eq_ignore_ascii_case("dnsTunneling") && value.trim() == "true"
+        })
+    })
+}
+
+/// Check whether an error was caused by an io::Error with kind PermissionDenied
+fn is_permission_denied(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::PermissionDenied)
+}
+
+/// Determine the host/hypervisor IP by reading the first nameserver from /etc/resolv.conf
+///
+/// This is what most basic answers/tutorials online suggest, and it's fine in a default
+/// configuration, but won't work in WSL setups that use a custom resolv.conf (e.g. when needing to
+/// add search domains or something, or for any other reason don't use the host as WSL's DNS)
+pub fn host_ip_from_resolv_conf() -> Result<IpAddr> {
+    host_ips_from_resolv_conf(None).map(|ips| ips[0])
+}
+
+/// How many more times to read /etc/resolv.conf while it's empty, and how long to wait before each
+const EMPTY_RESOLV_CONF_RETRIES: u32 = 3;
+const EMPTY_RESOLV_CONF_DELAY: Duration = Duration::from_millis(100);
+
+/// Read /etc/resolv.conf. WSL empties and rewrites it while setting up the network, so an empty
+/// file is read again a few times before giving up on it.
+fn read_resolv_conf() -> Result<String> {
+    read_resolv_conf_with(|path| fs::read(path))
+}
+
+/// read_resolv_conf(), reading the file with `read` so that tests can fake it
+fn read_resolv_conf_with(read: impl Fn(&Path) -> io::Result<Vec<u8>>) -> Result<String> {
+    for retry in 0..=EMPTY_RESOLV_CONF_RETRIES {
+        if retry > 0 {
+            debug!("/etc/resolv.conf is empty, reading it again in {:?}", EMPTY_RESOLV_CONF_DELAY);
+            sleep(EMPTY_RESOLV_CONF_DELAY);
+        }
+        let data =
+            read(Path::new("/etc/resolv.conf")).context("failed to read /etc/resolv.conf")?;
+        let contents = String::from_utf8(data).context("/etc/resolv.conf isn't valid utf8")?;
+        trace!("/etc/resolv.conf contents:\n{}", contents.trim_end());
+        if !contents.trim().is_empty() {
+            return Ok(contents);
+        }
+    }
+    bail!(
+        "/etc/resolv.conf is empty, WSL may still be setting up the network. Try again, or fall \
+         back to the route method with --method resolv-conf,route"
+    )
+}
+
+/// Get every nameserver from /etc/resolv.conf that could be the host, with those of the `prefer`
+/// address family first. Loopback stub resolvers like systemd-resolved's 127.0.0.53 are skipped.
+/// The list is never empty.
+pub fn host_ips_from_resolv_conf(prefer: Option<IpFamily>) -> Result<Vec<IpAddr>> {
+    resolv_conf_host_ips(&read_resolv_conf()?, prefer)
+}
+
+/// host_ips_from_resolv_conf(), given the contents of /etc/resolv.conf
+pub fn resolv_conf_host_ips(contents: &str, prefer: Option<IpFamily>) -> Result<Vec<IpAddr>> {
+    let mut ips = parse_resolv_conf_nameservers(contents);
+    ips.retain(|ip| {
+        if ip.is_loopback() {
+            debug!("skipping local stub resolver {} in /etc/resolv.conf", ip);
+        }
+        !ip.is_loopback()
+    });
+    if let Some(family) = prefer {
+        // stable sort, so the file's order is kept within each family
+        ips.sort_by_key(|ip| !family.matches(ip));
+    }
+    ensure!(!ips.is_empty(), "unable to find host IP address in /etc/resolv.conf");
+    Ok(ips)
+}
+
+/// Parse the contents of /etc/resolv.conf and return every valid nameserver IP address, in order
+pub fn parse_resolv_conf_nameservers(contents: &str) -> Vec<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_ascii_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => match addr.parse() {
+                    Ok(ip) => Some(ip),
+                    Err(e) => {
+                        debug!("ignoring invalid nameserver {:?} in /etc/resolv.conf: {}", addr, e);
+                        None
+                    }
+                },
+                (_, _) => None,
+            }
+        })
+        .collect()
+}
+
+/// Parse the contents of /etc/resolv.conf and return the first nameserver's IP address
+pub fn parse_resolv_conf(contents: &str) -> Result<IpAddr> {
+    ensure!(!contents.trim().is_empty(), "/etc/resolv.conf is empty");
+    contents
+        .lines()
+        .find_map(|line| {
+            let mut words = line.split_ascii_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => Some(addr),
+                (_, _) => None,
+            }
+        })
+        .ok_or_else(|| anyhow!("unable to find host IP address in /etc/resolv.conf"))?
+        .parse::<IpAddr>()
+        .context("unable to parse host IP address")
+}
+
+/// Look up the addresses of the --hostname, with the --dns-server if one was given, otherwise with
+/// the system resolver. The list is never empty.
+fn host_ips_from_hostname(args: &Args) -> Result<Vec<IpAddr>> {
+    let name =
+        args.hostname.as_deref().ok_or_else(|| anyhow!("the hostname method needs --hostname"))?;
+    let mut ips = match args.dns_server {
+        Some(server) => resolve_with(server, name, args.timeout)?,
+        None => (name, 0)
+            .to_socket_addrs()
+            .with_context(|| format!("failed to look up {name}"))?
+            .map(|sa| sa.ip())
+            .collect(),
+    };
+    if let Some(family) = args.prefer {
+        ips.sort_by_key(|ip| !family.matches(ip));
+    }
+    ensure!(!ips.is_empty(), "no addresses found for {name}");
+    debug!("{} has addresses {:?}", name, ips);
+    Ok(ips)
+}
+
+/// Determine the host/hypervisor IP by getting the default IPv4 route.
+///
+/// This parses `ip -4 -json route show default` and extracts the gateway IP address. It should be
+/// more reliable than the /etc/resolv.conf method, but could still fail in case a VPN client is
+/// running inside the WSL VM or something like that.
+///
+/// `table` selects a routing table other than main, with `all` every table's default gateway is
+/// collected and the main table's is preferred.
+///
+/// `interface` limits the routes to those through that network interface, e.g. to ignore a VPN's
+/// `tun0` default route.
+///
+/// Tested on iproute2 v5.9.0 on ubuntu 21.10. I think the json flag was added in v4.17 which was
+/// released in mid-2018, so a somewhat recent distro is needed.
+pub fn host_ip_from_route(table: Option<&str>, interface: Option<&str>) -> Result<IpAddr> {
+    route_gateway(table, interface, true)
+}
+
+/// host_ip_from_route(), but with `warn` false multiple default routes are only a debug message,
+/// for --no-route-warning
+fn route_gateway(table: Option<&str>, interface: Option<&str>, warn: bool) -> Result<IpAddr> {
+    let (gateways, routes) = route_gateways(table, interface)?;
+
+    // multiple routes are expected when merging tables, but not multiple distinct gateways
+    let ambiguous = if table == Some("all") { gateways.len() > 1 } else { routes.len() > 1 };
+    if ambiguous && warn {
+        warning!("ip route returned multiple defaults routes: {routes:?}");
+    } else if ambiguous {
+        debug!("using the first of multiple default routes: {routes:?}");
+    }
+
+    gateways
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("default gateway not found (or is not a string)"))
+}
+
+/// Run `ip route` and get the unique gateways of all the default routes, along with the routes
+/// themselves
+fn route_gateways(
+    table: Option<&str>,
+    interface: Option<&str>,
+) -> Result<(Vec<IpAddr>, Vec<DefaultRoute>)> {
+    let mut cmd = Command::new("ip");
+    cmd.args(["-4", "-json", "route", "show"]);
+    if let Some(table) = table {
+        cmd.args(["table", table]);
+    }
+    cmd.arg("default");
+    let output = run_ip(&mut cmd)?;
+    let routes = parse_route_json(&output)?;
+    debug!("{cmd:?} returned default routes:\n{routes:#?}");
+    let routes = usable_routes(routes, interface)?;
+    Ok((route_hosts(&routes, onlink_host_ip), routes))
+}
+
+/// Keep the default routes that could lead to the host: those through `interface` if it's given,
+/// and not those whose link is down. It's an error if that leaves none.
+fn usable_routes(
+    mut routes: Vec<DefaultRoute>,
+    interface: Option<&str>,
+) -> Result<Vec<DefaultRoute>> {
+    if let Some(interface) = interface {
+        routes.retain(|route| route.dev.as_deref() == Some(interface));
+        ensure!(!routes.is_empty(), "no default route uses interface {interface}");
+    }
+
+    // a route whose interface has no carrier can't reach the host, e.g. a disconnected ethernet
+    // port next to a working wifi connection
+    let (down, up): (Vec<_>, Vec<_>) = routes.into_iter().partition(DefaultRoute::is_linkdown);
+    if !down.is_empty() {
+        debug!("skipping default routes with link down: {down:?}");
+    }
+    ensure!(!up.is_empty(), "every default route's link is down");
+    Ok(up)
+}
+
+/// Get the unique host IPs that `routes` lead to, in order. `onlink_host` guesses the host on an
+/// interface for routes without a gateway.
+fn route_hosts(
+    routes: &[DefaultRoute],
+    onlink_host: impl Fn(&str) -> Result<Ipv4Addr>,
+) -> Vec<IpAddr> {
+    // collect the unique gateways of all the default routes. Normally there's only one, but every
+    // table might have one with `--route-table all`
+    let mut gateways = Vec::new();
+    for route in routes {
+        // Skip routes that have no gateway at all, like an unreachable default in some other table.
+        // A route without a gateway that goes through an interface is directly connected though
+        // (whether or not it's flagged onlink), and the host is most likely the first address of
+        // the interface's subnet. An onlink route with a gateway is used as-is.
+        let gateway = match (route.gateway, route.dev.as_deref()) {
+            (Some(gateway), _) => gateway,
+            (None, Some(dev)) if dev != "lo" => match onlink_host(dev) {
+                Ok(ip) => {
+                    let kind = if route.is_onlink() { "onlink" } else { "directly connected" };
+                    debug!("default route via {dev} is {kind}, guessing host {ip}");
+                    IpAddr::V4(ip)
+                }
+                Err(e) => {
+                    debug!("skipping directly connected default route via {dev}: {e:#}");
+                    continue;
+                }
+            },
+            (None, _) => {
+                debug!("skipping default route without a gateway: {route:?}");
+                continue;
+            }
+        };
+        debug!("default gateway {} in table {}", gateway, route.table.as_deref().unwrap_or("main"));
+        if !gateways.contains(&gateway) {
+            gateways.push(gateway);
+        }
+    }
+    gateways
+}
+
+/// Determine the host IP from the first network interface matching `pattern` that has a default
+/// route (in any table) or an IPv4 subnet. Unlike the route method, other interfaces' default
+/// routes don't matter, so Docker or a VPN taking over the default route doesn't confuse it.
+fn host_ip_from_bridge(pattern: &str) -> Result<IpAddr> {
+    let mut names: Vec<String> = fs::read_dir("/sys/class/net")
+        .context("failed to list network interfaces")?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| glob_match(pattern, name))
+        .collect();
+    ensure!(!names.is_empty(), "no network interface matches {pattern}");
+    names.sort();
+    for name in &names {
+        let gateway = match route_gateways(Some("all"), Some(name)) {
+            Ok((gateways, _)) if !gateways.is_empty() => Ok(gateways[0]),
+            _ => onlink_host_ip(name).map(IpAddr::V4),
+        };
+        match gateway {
+            Ok(ip) => {
+                debug!("bridge interface {} has host IP {}", name, ip);
+                return Ok(ip);
+            }
+            Err(e) => debug!("no host IP for interface {}: {:#}", name, e),
+        }
+    }
+    bail!("no host IP found for interfaces matching {pattern} ({})", names.join(", "))
+}
+
+/// Determine the host IP from the default route in /proc/net/route, for the proc-route method.
+/// It's the same gateway as the route method finds in the main table, without running `ip`.
+fn host_ip_from_proc_route() -> Result<IpAddr> {
+    let contents =
+        fs::read_to_string("/proc/net/route").context("failed to read /proc/net/route")?;
+    trace!("/proc/net/route contents:\n{}", contents.trim_end());
+    parse_proc_route(&contents).map(IpAddr::V4)
+}
+
+/// Match `name` against a shell-style `pattern` where `*` matches any run of characters
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            // try every split point for what the star swallows
+            (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// Guess the host IP for a directly connected default route through `dev`, by the convention
+/// that the host takes the first address of the subnet (the `.1`)
+fn onlink_host_ip(dev: &str) -> Result<Ipv4Addr> {
+    let mut cmd = Command::new("ip");
+    cmd.args(["-4", "-json", "addr", "show", "dev", dev]);
+    let (local, prefixlen) = parse_addr_json(&run_ip(&mut cmd)?)?;
+    subnet_host_ip(local, prefixlen).with_context(|| format!("can't guess the host on {dev}"))
+}
+
+/// The first address of the subnet that the interface address `local/prefixlen` is in, which
+/// is the host's by convention. It's an error if that's `local` itself.
+fn subnet_host_ip(local: Ipv4Addr, prefixlen: u8) -> Result<Ipv4Addr> {
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefixlen)).unwrap_or(0);
+    let host = Ipv4Addr::from((u32::from(local) & mask) + 1);
+    ensure!(host != local, "{local}/{prefixlen} is the first address of its subnet itself");
+    Ok(host)
+}
+
+/// Parse the output of `ip -4 -json addr show dev <dev>` and return the interface's first IPv4
+/// address and its prefix length. Subnets too small to have a separate host address are an error.
+///
+/// Like the other parsers, this must never panic.
+pub fn parse_addr_json(output: &[u8]) -> Result<(Ipv4Addr, u8)> {
+    let js: Value =
+        serde_json::from_reader(Cursor::new(output)).context("failed to parse output as JSON")?;
+    let addr = js
+        .as_array()
+        .and_then(|links| links.first())
+        .and_then(|link| link.get("addr_info"))
+        .and_then(Value::as_array)
+        .and_then(|addrs| {
+            addrs.iter().find(|addr| addr.get("family").and_then(Value::as_str) == Some("inet"))
+        })
+        .ok_or_else(|| anyhow!("interface has no IPv4 address"))?;
+    let local = addr
+        .get("local")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("address has no local field: {addr}"))?
+        .parse::<Ipv4Addr>()
+        .context("failed to parse interface address")?;
+    let prefixlen = addr
+        .get("prefixlen")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| anyhow!("address has no prefixlen: {addr}"))?;
+    ensure!(prefixlen <= 30, "subnet {local}/{prefixlen} is too small to guess the host in");
+    Ok((local, prefixlen as u8))
+}
+
+/// Determine the host/hypervisor IP from the gateway `ip -json route get` says traffic to `dst`
+/// would use. With policy routing this can differ from the default route's gateway.
+pub fn host_ip_from_route_get(dst: IpAddr) -> Result<IpAddr> {
+    let mut cmd = Command::new("ip");
+    cmd.args(["-json", "route", "get", &dst.to_string()]);
+    let output = run_ip(&mut cmd)?;
+    let gateway = parse_route_get_json(&output)?;
+    debug!("route to {} goes through gateway {}", dst, gateway);
+    Ok(gateway)
+}
+
+/// Parse the output of `ip -json route get` and return the gateway of the route.
+///
+/// Like parse_route_json(), this must never panic.
+pub fn parse_route_get_json(output: &[u8]) -> Result<IpAddr> {
+    let js: Value =
+        serde_json::from_reader(Cursor::new(output)).context("failed to parse output as JSON")?;
+    // iproute2 prints a one-element array, but accept the bare object too
+    let route = match &js {
+        Value::Array(values) => values.first().ok_or_else(|| anyhow!("empty json array"))?,
+        object @ Value::Object(_) => object,
+        other => bail!("expected JSON array or object, got {other}"),
+    };
+    let direct = || anyhow!("route has no gateway, the destination is directly reachable");
+    let gateway = route.get("gateway").ok_or_else(direct)?;
+    let gateway = gateway
+        .as_str()
+        .ok_or_else(|| anyhow!("gateway is not a string: {gateway}"))?
+        .parse::<IpAddr>()
+        .context("failed to parse gateway IP address")?;
+    ensure!(!gateway.is_unspecified(), direct());
+    Ok(gateway)
+}
+
+/// Run an `ip` command and return its output, which is logged. The command runs in the C locale
+/// so that its output doesn't depend on the user's language settings.
+pub(crate) fn run_ip(cmd: &mut Command) -> Result<Vec<u8>> {
+    cmd.env("LC_ALL", "C").env("LANG", "C").stdout(Stdio::piped()).stderr(Stdio::inherit());
+    let output = cmd.output().with_context(|| format!("failed to execute {cmd:?}"))?;
+    if !output.status.success() {
+        bail!("command {cmd:?} failed");
+    }
+    debug!("{cmd:?} raw output:\n{}", String::from_utf8_lossy(&output.stdout).trim_end());
+    Ok(output.stdout)
+}
+
+/// A default route from the output of `ip -json route show default`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultRoute {
+    /// The gateway address, if there is one
+    pub gateway: Option<IpAddr>,
+    /// The interface name
+    pub dev: Option<String>,
+    /// The routing table, None for the main table
+    pub table: Option<String>,
+    /// Route flags such as "onlink" or "linkdown"
+    pub flags: Vec<String>,
+}
+
+impl DefaultRoute {
+    /// Whether the route has the given flag
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    /// Whether the route's interface has no carrier, so the route is unusable
+    pub fn is_linkdown(&self) -> bool {
+        self.has_flag("linkdown")
+    }
+
+    /// Whether the route's gateway (or lack of one) is directly reachable on its interface
+    pub fn is_onlink(&self) -> bool {
+        self.has_flag("onlink")
+    }
+}
+
+/// Parse the output of `ip -json route show default`, with the main table's routes first.
+///
+/// The output comes from an external program, so it's validated carefully and this must never
+/// panic, no matter what garbage it's given.
+pub fn parse_route_json(output: &[u8]) -> Result<Vec<DefaultRoute>> {
+    let js: Value =
+        serde_json::from_reader(Cursor::new(output)).context("failed to parse output as JSON")?;
+
+    // unwrap inner objects out of outer array. Some builds and wrappers print a lone route as a
+    // bare object, which is the same as a one-element array.
+    let values = match js {
+        Value::Array(values) => values,
+        object @ Value::Object(_) => vec![object],
+        other => bail!("expected JSON array or object, got {other}"),
+    };
+    if values.is_empty() {
+        bail!("empty json array");
+    }
+
+    let mut routes = Vec::with_capacity(values.len());
+    for route in &values {
+        // sanity check, "dst" field should be "default"
+        ensure!(
+            matches!(route.get("dst").and_then(Value::as_str), Some("default")),
+            "route destination is not 'default': {route}"
+        );
+
+        // extract and parse the gateway field as an IP. Some onlink routes have a gateway of
+        // 0.0.0.0, which means the same as no gateway.
+        let gateway = match route.get("gateway") {
+            None => None,
+            Some(gateway) => Some(
+                gateway
+                    .as_str()
+                    .ok_or_else(|| anyhow!("default gateway is not a string: {gateway}"))?
+                    .parse::<IpAddr>()
+                    .context("failed to parse default gateway IP address")?,
+            )
+            .filter(|gateway| !gateway.is_unspecified()),
+        };
+
+        let string_field = |name| route.get(name).and_then(Value::as_str).map(str::to_owned);
+        let flags = match route.get("flags") {
+            None => Vec::new(),
+            Some(Value::Array(flags)) => {
+                flags.iter().filter_map(Value::as_str).map(str::to_owned).collect()
+            }
+            Some(flags) => bail!("route flags are not an array: {flags}"),
+        };
+
+        routes.push(DefaultRoute {
+            gateway,
+            dev: string_field("dev"),
+            table: string_field("table").filter(|table| table != "main"),
+            flags,
+        });
+    }
+
+    // stable sort so that main table routes come first
+    routes.sort_by_key(|route| route.table.is_some());
+    Ok(routes)
+}
+
+/// Parse the contents of /proc/net/route and return the gateway of the default route with the
+/// lowest metric. Routes that aren't up or have no gateway are ignored.
+///
+/// Addresses in this file are hex dumps of the kernel's network byte order u32, printed in host
+/// byte order, so on x86 the gateway 192.168.2.1 shows up as `0102A8C0`. Like the other parsers,
+/// this must never panic.
+pub fn parse_proc_route(contents: &str) -> Result<Ipv4Addr> {
+    // from linux/route.h
+    const RTF_UP: u16 = 0x0001;
+    const RTF_GATEWAY: u16 = 0x0002;
+
+    let parse_addr =
+        |hex: &str| u32::from_str_radix(hex, 16).map(|n| Ipv4Addr::from(n.to_ne_bytes()));
+
+    let mut best: Option<(u32, Ipv4Addr)> = None;
+    // the first line is the column headings
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+        let [iface, dest, gateway, flags, _refcnt, _use, metric, ..] = fields[..] else {
+            if !line.trim().is_empty() {
+                debug!("skipping short /proc/net/route line: {line:?}");
+            }
+            continue;
+        };
+        if parse_addr(dest).context("invalid route destination")? != Ipv4Addr::UNSPECIFIED {
+            continue;
+        }
+        let flags = u16::from_str_radix(flags, 16).context("invalid route flags")?;
+        if flags & RTF_UP == 0 || flags & RTF_GATEWAY == 0 {
+            debug!("skipping default route via {iface} that is down or has no gateway");
+            continue;
+        }
+        let gateway = parse_addr(gateway).context("invalid route gateway")?;
+        let metric: u32 = metric.parse().context("invalid route metric")?;
+        debug!("default gateway {} via {} with metric {}", gateway, iface, metric);
+        if best.is_none_or(|(best_metric, _)| metric < best_metric) {
+            best = Some((metric, gateway));
+        }
+    }
+    best.map(|(_, gateway)| gateway).ok_or_else(|| anyhow!("no default route in /proc/net/route"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::take_warnings;
+
+    /// A dual-stack WSL resolv.conf, with a stub resolver and an IPv6 nameserver first
+    const MIXED_RESOLV_CONF: &str = "\
+# This file was automatically generated by WSL.
+nameserver 127.0.0.53
+nameserver fd00::1
+nameserver 172.28.160.1
+nameserver fe80::1
+nameserver 10.0.0.1
+";
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn resolv_conf_mixed_families_in_file_order() {
+        let got = resolv_conf_host_ips(MIXED_RESOLV_CONF, None).unwrap();
+        assert_eq!(got, ips(&["fd00::1", "172.28.160.1", "fe80::1", "10.0.0.1"]));
+    }
+
+    #[test]
+    fn resolv_conf_mixed_families_prefer_ipv4() {
+        let got = resolv_conf_host_ips(MIXED_RESOLV_CONF, Some(IpFamily::Ipv4)).unwrap();
+        assert_eq!(got, ips(&["172.28.160.1", "10.0.0.1", "fd00::1", "fe80::1"]));
+    }
+
+    #[test]
+    fn resolv_conf_mixed_families_prefer_ipv6() {
+        let got = resolv_conf_host_ips(MIXED_RESOLV_CONF, Some(IpFamily::Ipv6)).unwrap();
+        assert_eq!(got, ips(&["fd00::1", "fe80::1", "172.28.160.1", "10.0.0.1"]));
+    }
+
+    #[test]
+    fn resolv_conf_permission_denied_falls_back() {
+        let denied = |_: &Path| Err(io::Error::from(ErrorKind::PermissionDenied));
+        let fallback: IpAddr = "172.28.160.1".parse().unwrap();
+        let mut detector = Detector::empty();
+        detector
+            .register_method(
+                "resolv-conf",
+                Box::new(move || {
+                    let contents = read_resolv_conf_with(denied)?;
+                    Ok(resolv_conf_host_ips(&contents, None)?[0])
+                }),
+            )
+            .register_method("route", Box::new(move || Ok(fallback)));
+
+        assert_eq!(detector.detect().unwrap(), (fallback, "route"));
+        // other tests might have warnings too
+        let warnings: Vec<String> = take_warnings().iter().map(ToString::to_string).collect();
+        let expected = "failed to read /etc/resolv.conf: permission denied, falling back to the \
+                        route method";
+        assert!(warnings.iter().any(|w| w == expected), "unexpected warnings {warnings:?}");
+    }
+
+    #[test]
+    fn resolv_conf_permission_denied_without_fallback() {
+        let denied = |_: &Path| Err(io::Error::from(ErrorKind::PermissionDenied));
+        let e = read_resolv_conf_with(denied).unwrap_err();
+        assert!(is_permission_denied(&e));
+        assert_eq!(format!("{e:#}"), "failed to read /etc/resolv.conf: permission denied");
+    }
+
+    /// `ip -json route show default` for a directly connected default route without a gateway
+    const ROUTE_JSON_ONLINK: &str =
+        r#"[{"dst":"default","dev":"eth0","protocol":"kernel","scope":"link","flags":["onlink"]}]"#;
+
+    /// `ip -json route show default` for a directly connected default route with a 0.0.0.0
+    /// gateway, from a WSL distro with a custom network setup
+    const ROUTE_JSON_UNSPECIFIED_GATEWAY: &str =
+        r#"[{"dst":"default","gateway":"0.0.0.0","dev":"eth0","flags":["onlink"]}]"#;
+
+    #[test]
+    fn route_json_onlink_without_gateway() {
+        let routes = parse_route_json(ROUTE_JSON_ONLINK.as_bytes()).unwrap();
+        assert_eq!(
+            routes,
+            [DefaultRoute {
+                gateway: None,
+                dev: Some("eth0".to_owned()),
+                table: None,
+                flags: vec!["onlink".to_owned()],
+            }]
+        );
+        assert!(routes[0].is_onlink());
+    }
+
+    #[test]
+    fn route_json_unspecified_gateway() {
+        let routes = parse_route_json(ROUTE_JSON_UNSPECIFIED_GATEWAY.as_bytes()).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].gateway, None, "0.0.0.0 means no gateway");
+        assert_eq!(routes[0].dev.as_deref(), Some("eth0"));
+    }
+
+    #[test]
+    fn route_json_bare_object() {
+        let json = r#"{"dst":"default","gateway":"172.28.160.1","dev":"eth0","flags":[]}"#;
+        let routes = parse_route_json(json.as_bytes()).unwrap();
+        assert_eq!(routes, parse_route_json(format!("[{json}]").as_bytes()).unwrap());
+        assert_eq!(routes[0].gateway, Some("172.28.160.1".parse().unwrap()));
+        assert!(parse_route_json(b"[]").is_err());
+        assert!(parse_route_json(br#""default""#).is_err());
+    }
+
+    /// `ip -json route show default` on a laptop with both ethernet and wifi, with the ethernet
+    /// cable unplugged
+    const ROUTE_JSON_LINKDOWN: &str = r#"[
+        {"dst":"default","gateway":"192.168.1.1","dev":"eth0","protocol":"dhcp","metric":100,
+         "flags":["linkdown"]},
+        {"dst":"default","gateway":"172.28.160.1","dev":"eth1","protocol":"dhcp","metric":600,
+         "flags":[]}
+    ]"#;
+
+    /// Guess the host on any interface like WSL's eth0 would have it
+    fn fake_onlink_host(dev: &str) -> Result<Ipv4Addr> {
+        match dev {
+            "eth0" => Ok(Ipv4Addr::new(172, 28, 160, 1)),
+            _ => bail!("{dev} has no IPv4 address"),
+        }
+    }
+
+    #[test]
+    fn route_json_linkdown_skipped() {
+        let routes = parse_route_json(ROUTE_JSON_LINKDOWN.as_bytes()).unwrap();
+        assert!(routes[0].is_linkdown());
+        assert!(!routes[1].is_linkdown());
+        let usable = usable_routes(routes.clone(), None).unwrap();
+        assert_eq!(usable, &routes[1..]);
+        assert_eq!(
+            route_hosts(&usable, fake_onlink_host),
+            ["172.28.160.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn route_json_linkdown_only() {
+        let routes = parse_route_json(ROUTE_JSON_LINKDOWN.as_bytes()).unwrap();
+        let e = usable_routes(routes.clone(), Some("eth0")).unwrap_err();
+        assert_eq!(e.to_string(), "every default route's link is down");
+        assert!(usable_routes(routes, Some("wlan0")).is_err());
+    }
+
+    #[test]
+    fn route_json_onlink_guesses_host() {
+        let routes = parse_route_json(ROUTE_JSON_ONLINK.as_bytes()).unwrap();
+        let usable = usable_routes(routes, None).unwrap();
+        assert_eq!(
+            route_hosts(&usable, fake_onlink_host),
+            ["172.28.160.1".parse::<IpAddr>().unwrap()]
+        );
+        // no guess on an interface without an address
+        let routes =
+            parse_route_json(ROUTE_JSON_ONLINK.replace("eth0", "eth9").as_bytes()).unwrap();
+        assert!(route_hosts(&routes, fake_onlink_host).is_empty());
+    }
+
+    #[test]
+    fn route_json_onlink_gateway_used_as_is() {
+        let json = r#"[{"dst":"default","gateway":"10.0.0.1","dev":"eth0","flags":["onlink"]}]"#;
+        let routes = parse_route_json(json.as_bytes()).unwrap();
+        assert!(routes[0].is_onlink());
+        assert_eq!(route_hosts(&routes, fake_onlink_host), ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn subnet_host_is_first_address() {
+        let host = |local: &str, prefixlen| subnet_host_ip(local.parse().unwrap(), prefixlen);
+        assert_eq!(host("172.28.167.42", 20).unwrap(), Ipv4Addr::new(172, 28, 160, 1));
+        assert_eq!(host("192.168.1.2", 24).unwrap(), Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(host("10.0.0.6", 30).unwrap(), Ipv4Addr::new(10, 0, 0, 5));
+        assert!(host("192.168.1.1", 24).is_err(), "the interface is the .1 itself");
+    }
+
+    #[test]
+    fn resolv_conf_only_stub_resolvers() {
+        let contents = "nameserver 127.0.0.53\nnameserver ::1\n";
+        assert!(resolv_conf_host_ips(contents, Some(IpFamily::Ipv4)).is_err());
+    }
+
+    #[test]
+    fn resolv_conf_empty() {
+        let reads = Cell::new(0);
+        let empty = |_: &Path| {
+            reads.set(reads.get() + 1);
+            Ok(b" \n\t\n".to_vec())
+        };
+        let e = read_resolv_conf_with(empty).unwrap_err();
+        assert!(e.to_string().starts_with("/etc/resolv.conf is empty"), "{e}");
+        assert_eq!(reads.get(), EMPTY_RESOLV_CONF_RETRIES + 1);
+        assert_eq!(parse_resolv_conf("").unwrap_err().to_string(), "/etc/resolv.conf is empty");
+    }
+
+    #[test]
+    fn resolv_conf_filled_in_while_waiting() {
+        let reads = Cell::new(0);
+        let filled_later = |_: &Path| {
+            reads.set(reads.get() + 1);
+            Ok(if reads.get() < 2 { Vec::new() } else { MIXED_RESOLV_CONF.as_bytes().to_vec() })
+        };
+        assert_eq!(read_resolv_conf_with(filled_later).unwrap(), MIXED_RESOLV_CONF);
+        assert_eq!(reads.get(), 2);
+    }
+
+    #[test]
+    fn resolv_conf_without_nameservers() {
+        let contents = "# generated by WSL\nsearch example.com\n";
+        let e = resolv_conf_host_ips(contents, None).unwrap_err();
+        assert_eq!(e.to_string(), "unable to find host IP address in /etc/resolv.conf");
+        assert!(parse_resolv_conf(contents).is_err());
+    }
+
+    /// The column headings line of /proc/net/route
+    const PROC_ROUTE_HEADER: &str =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT";
+
+    /// /proc/net/route with PROC_ROUTE_HEADER and `routes`
+    fn proc_route(routes: &[&str]) -> String {
+        let mut contents = PROC_ROUTE_HEADER.to_owned();
+        for route in routes {
+            contents = contents + "\n" + route;
+        }
+        contents + "\n"
+    }
+
+    /// eth1's default route that isn't up, from a WSL2 machine with the cable unplugged
+    const ROUTE_DOWN: &str = "eth1\t00000000\t01011FAC\t0002\t0\t0\t0\t00000000\t0\t0\t0";
+    /// A default route without RTF_GATEWAY
+    const ROUTE_NO_GATEWAY: &str = "eth2\t00000000\t00000000\t0001\t0\t0\t0\t00000000\t0\t0\t0";
+    /// A VPN's default route via 10.8.0.1, with metric 5
+    const ROUTE_VPN: &str = "tun0\t00000000\t0100080A\t0003\t0\t0\t5\t00000000\t0\t0\t0";
+    /// WSL's default route via 192.168.112.1, with metric 0
+    const ROUTE_ETH0: &str = "eth0\t00000000\t0170A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0";
+    /// eth0's subnet route, which isn't a default route
+    const ROUTE_SUBNET: &str = "eth0\t0070A8C0\t00000000\t0001\t0\t0\t0\t00F0FFFF\t0\t0\t0";
+
+    // the addresses are in the byte order of the machine that wrote the file, these are from x86
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn proc_route_lowest_metric() {
+        let contents =
+            proc_route(&[ROUTE_DOWN, ROUTE_NO_GATEWAY, ROUTE_VPN, ROUTE_ETH0, ROUTE_SUBNET]);
+        assert_eq!(parse_proc_route(&contents).unwrap(), Ipv4Addr::new(192, 168, 112, 1));
+        let contents = proc_route(&[ROUTE_VPN, ROUTE_SUBNET]);
+        assert_eq!(parse_proc_route(&contents).unwrap(), Ipv4Addr::new(10, 8, 0, 1));
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn proc_route_hex_decoding() {
+        let contents = proc_route(&["eth0\t00000000\t01A0BCAC\t0003\t0\t0\t0\t00000000"]);
+        assert_eq!(parse_proc_route(&contents).unwrap(), Ipv4Addr::new(172, 188, 160, 1));
+    }
+
+    #[test]
+    fn proc_route_skips_down_and_gatewayless() {
+        assert!(parse_proc_route(&proc_route(&[ROUTE_DOWN])).is_err());
+        assert!(parse_proc_route(&proc_route(&[ROUTE_NO_GATEWAY])).is_err());
+        assert!(parse_proc_route(&proc_route(&[ROUTE_SUBNET])).is_err());
+    }
+
+    #[test]
+    fn proc_route_invalid() {
+        assert!(parse_proc_route("").is_err());
+        assert!(parse_proc_route(PROC_ROUTE_HEADER).is_err());
+        let bad_gateway = proc_route(&["eth0\t00000000\tnothex\t0003\t0\t0\t0\t00000000"]);
+        assert!(parse_proc_route(&bad_gateway).is_err());
+        let bad_metric = proc_route(&["eth0\t00000000\t0170A8C0\t0003\t0\t0\t-1\t00000000"]);
+        assert!(parse_proc_route(&bad_metric).is_err());
+    }
+}
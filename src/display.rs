@@ -0,0 +1,377 @@
+//! Formatting and parsing of DISPLAY strings
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::{IpFormat, Shell};
+
+/// Format a DISPLAY string for the given host, display number, and optional screen number. IPv6
+/// addresses are bracketed so that the colons in the address aren't confused with the display
+/// separator. A host of None means a local display like `:1`
+pub fn format_display(host: Option<IpAddr>, display: u16, screen: Option<u16>) -> String {
+    format_display_brackets(host, display, screen, true)
+}
+
+/// Like format_display(), but IPv6 addresses are only bracketed if `brackets` is true. Leaving
+/// them bare isn't standard, it's only for X clients that can't handle the brackets.
+pub fn format_display_brackets(
+    host: Option<IpAddr>,
+    display: u16,
+    screen: Option<u16>,
+    brackets: bool,
+) -> String {
+    let host = match host {
+        None => String::new(),
+        Some(IpAddr::V6(ip)) if brackets => format!("[{ip}]"),
+        Some(ip) => ip.to_string(),
+    };
+    join_display(&host, display, screen)
+}
+
+/// Put together an already formatted host with the display and screen numbers
+fn join_display(host: &str, display: u16, screen: Option<u16>) -> String {
+    match screen {
+        Some(screen) => format!("{host}:{display}.{screen}"),
+        None => format!("{host}:{display}"),
+    }
+}
+
+/// Format `ip` for --ip-format
+pub fn format_ip(ip: IpAddr, format: IpFormat) -> String {
+    match (format, ip) {
+        (IpFormat::Dotted, ip) => ip.to_string(),
+        (IpFormat::Int, IpAddr::V4(ip)) => u32::from(ip).to_string(),
+        (IpFormat::Int, IpAddr::V6(ip)) => u128::from(ip).to_string(),
+        (IpFormat::Arpa, IpAddr::V4(ip)) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        (IpFormat::Arpa, IpAddr::V6(ip)) => {
+            // one label per nibble, least significant first
+            let nibbles = format!("{:032x}", u128::from(ip));
+            let mut name: String = nibbles.chars().rev().flat_map(|c| [c, '.']).collect();
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Format a PULSE_SERVER string like `tcp:172.16.0.1:4713` for a PulseAudio server on `host`
+pub fn format_pulse_server(host: IpAddr, port: u16) -> String {
+    match host {
+        IpAddr::V4(ip) => format!("tcp:{ip}:{port}"),
+        IpAddr::V6(ip) => format!("tcp:[{ip}]:{port}"),
+    }
+}
+
+/// Format a DISPLAY string for an X server listening on the Unix socket at `path`, like
+/// `/path/to/socket:1`. Not every X client library understands this form, but libxcb does.
+pub fn format_socket_display(path: &Path, display: u16, screen: Option<u16>) -> String {
+    join_display(&path.display().to_string(), display, screen)
+}
+
+/// Format a shell command to set and export the environment variable `name` to `value`, for
+/// running with `eval`
+pub fn format_export(shell: Shell, name: &str, value: &str) -> String {
+    let quoted = shell_quote(shell, value);
+    match shell {
+        Shell::Sh => format!("export {name}={quoted}"),
+        Shell::Fish => format!("set -gx {name} {quoted}"),
+        Shell::Csh => format!("setenv {name} {quoted}"),
+    }
+}
+
+/// Quote `value` in single quotes as a single word for `shell`
+fn shell_quote(shell: Shell, value: &str) -> String {
+    let quoted = match shell {
+        // nothing is special inside single quotes, so end the quote to get a literal one
+        Shell::Sh => value.replace('\'', r"'\''"),
+        // backslashes escape single quotes and themselves inside single quotes
+        Shell::Fish => value.replace('\\', r"\\").replace('\'', r"\'"),
+        // history substitution still happens inside single quotes, and a newline ends the command
+        // unless it's escaped
+        Shell::Csh => value.replace('\'', r"'\''").replace('!', r"\!").replace('\n', "\\\n"),
+    };
+    format!("'{quoted}'")
+}
+
+/// Format the definition of a `wsl2_display` shell function, for --emit-function. The first call
+/// runs `command` (which should print --export output) and evaluates it, later calls see DISPLAY
+/// already set and skip that. Any arguments are run as a command afterwards, so
+/// `wsl2_display xterm` works from the start.
+///
+/// csh has no functions, so it's an error.
+pub fn format_function(shell: Shell, command: &[String]) -> Result<String> {
+    let command: Vec<String> = command.iter().map(|arg| shell_quote(shell, arg)).collect();
+    let command = command.join(" ");
+    Ok(match shell {
+        Shell::Sh => format!(
+            "wsl2_display() {{\n\
+             \x20   if [ -z \"${{DISPLAY-}}\" ]; then\n\
+             \x20       eval \"$({command})\"\n\
+             \x20   fi\n\
+             \x20   if [ $# -gt 0 ]; then\n\
+             \x20       \"$@\"\n\
+             \x20   fi\n\
+             }}"
+        ),
+        Shell::Fish => format!(
+            "function wsl2_display\n\
+             \x20   if not set -q DISPLAY\n\
+             \x20       {command} | source\n\
+             \x20   end\n\
+             \x20   if set -q argv[1]\n\
+             \x20       $argv\n\
+             \x20   end\n\
+             end"
+        ),
+        Shell::Csh => bail!("csh has no shell functions, use --export instead"),
+    })
+}
+
+/// Parse a DISPLAY string like `host:1` or `host:1.0` into its host, display number, and screen
+/// number (if present).
+///
+/// The host can be an IPv4 address, a hostname, an IPv6 address (bracketed, optionally with a
+/// `%zone` suffix, or bare in which case the last colon separates the display number), an absolute
+/// Unix socket path, or empty for a local display like `:0`.
+pub fn parse_display(s: &str) -> Result<(String, u16, Option<u16>)> {
+    let (host, rest) = if let Some(bracketed) = s.strip_prefix('[') {
+        let (host, rest) =
+            bracketed.split_once(']').ok_or_else(|| anyhow!("unclosed '[' in display '{s}'"))?;
+        let rest = rest
+            .strip_prefix(':')
+            .ok_or_else(|| anyhow!("expected ':' after ']' in display '{s}'"))?;
+        check_ipv6_host(host, s)?;
+        (host, rest)
+    } else {
+        let (host, rest) =
+            s.rsplit_once(':').ok_or_else(|| anyhow!("missing ':' in display '{s}'"))?;
+        if host.starts_with('/') {
+            // socket path, anything goes
+        } else if host.contains(':') {
+            check_ipv6_host(host, s)?;
+        } else {
+            ensure!(
+                host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b)),
+                "invalid hostname '{host}' in display '{s}'"
+            );
+        }
+        (host, rest)
+    };
+
+    let (display, screen) = match rest.split_once('.') {
+        Some((display, screen)) => (display, Some(screen)),
+        None => (rest, None),
+    };
+    let display = display
+        .parse::<u16>()
+        .with_context(|| format!("invalid display number '{display}' in display '{s}'"))?;
+    let screen = screen
+        .map(|screen| {
+            screen
+                .parse::<u16>()
+                .with_context(|| format!("invalid screen number '{screen}' in display '{s}'"))
+        })
+        .transpose()?;
+
+    Ok((host.to_owned(), display, screen))
+}
+
+/// Check that `host` from the display `s` is an IPv6 address, optionally with a `%zone` suffix
+/// naming the interface of a link-local address
+fn check_ipv6_host(host: &str, s: &str) -> Result<()> {
+    let (addr, zone) = match host.split_once('%') {
+        Some((addr, zone)) => (addr, Some(zone)),
+        None => (host, None),
+    };
+    addr.parse::<Ipv6Addr>()
+        .with_context(|| format!("invalid IPv6 address '{host}' in display '{s}'"))?;
+    if let Some(zone) = zone {
+        ensure!(
+            !zone.is_empty()
+                && zone.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b)),
+            "invalid IPv6 zone '{zone}' in display '{s}'"
+        );
+    }
+    Ok(())
+}
+
+/// Parse the DISPLAY string `s` and format it again the way format_display() would: IPv6
+/// addresses bracketed and compressed, and no leading zeros or `+` in the numbers. Hostnames,
+/// socket paths, and IPv6 zones are kept as they are.
+///
+/// Every string from format_display() comes back unchanged, and parse_display() gives back the
+/// host, display, and screen it was formatted from.
+pub fn normalize_display(s: &str) -> Result<String> {
+    let (host, display, screen) = parse_display(s)?;
+    let (addr, zone) = match host.split_once('%') {
+        Some((addr, zone)) => (addr, Some(zone)),
+        None => (host.as_str(), None),
+    };
+    let host = match (addr.parse::<IpAddr>(), zone) {
+        (Ok(IpAddr::V6(ip)), Some(zone)) => format!("[{ip}%{zone}]"),
+        (Ok(IpAddr::V6(ip)), None) => format!("[{ip}]"),
+        (Ok(IpAddr::V4(ip)), _) => ip.to_string(),
+        // a hostname, socket path, or nothing for a local display
+        (Err(_), _) => host,
+    };
+    Ok(join_display(&host, display, screen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(s: &str) -> (String, u16, Option<u16>) {
+        parse_display(s).unwrap_or_else(|e| panic!("failed to parse '{s}': {e:#}"))
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        assert_eq!(parsed("172.16.0.1:0"), ("172.16.0.1".to_owned(), 0, None));
+        assert_eq!(parsed("10.0.0.1:12"), ("10.0.0.1".to_owned(), 12, None));
+    }
+
+    #[test]
+    fn parse_local() {
+        assert_eq!(parsed(":1"), (String::new(), 1, None));
+        assert_eq!(parsed(":0.1"), (String::new(), 0, Some(1)));
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        assert_eq!(parsed("[fd00::1]:1"), ("fd00::1".to_owned(), 1, None));
+        assert_eq!(parsed("[::1]:0.2"), ("::1".to_owned(), 0, Some(2)));
+        // bare, the last colon separates the display number
+        assert_eq!(parsed("fd00::1:1"), ("fd00::1".to_owned(), 1, None));
+    }
+
+    #[test]
+    fn parse_ipv6_zone() {
+        assert_eq!(parsed("[fe80::1%eth0]:0"), ("fe80::1%eth0".to_owned(), 0, None));
+        assert_eq!(parsed("fe80::1%eth0:1.0"), ("fe80::1%eth0".to_owned(), 1, Some(0)));
+    }
+
+    #[test]
+    fn parse_hostname() {
+        assert_eq!(parsed("localhost:0"), ("localhost".to_owned(), 0, None));
+        assert_eq!(
+            parsed("win-host.mshome.net:1.0"),
+            ("win-host.mshome.net".to_owned(), 1, Some(0))
+        );
+    }
+
+    #[test]
+    fn parse_screen() {
+        assert_eq!(parsed("172.16.0.1:0.0"), ("172.16.0.1".to_owned(), 0, Some(0)));
+        assert_eq!(parsed("172.16.0.1:3.65535"), ("172.16.0.1".to_owned(), 3, Some(65535)));
+    }
+
+    #[test]
+    fn parse_socket_path() {
+        assert_eq!(parsed("/tmp/.X11-unix/X0:0"), ("/tmp/.X11-unix/X0".to_owned(), 0, None));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "",
+            "172.16.0.1",
+            "172.16.0.1:",
+            "172.16.0.1:x",
+            "172.16.0.1:65536",
+            "172.16.0.1:0.",
+            "172.16.0.1:0.x",
+            "172.16.0.1:-1",
+            "bad host:0",
+            "[fd00::1",
+            "[fd00::1]0",
+            "[fd00::1]:",
+            "[not-ipv6]:0",
+            "[172.16.0.1]:0",
+            "[fe80::1%]:0",
+            "[fe80::1%eth 0]:0",
+            "fd00::zz:0",
+        ] {
+            assert!(parse_display(s).is_err(), "'{s}' should fail to parse");
+        }
+    }
+
+    /// A sample of hosts covering every kind of address format_display_brackets() handles
+    fn sample_hosts() -> Vec<Option<IpAddr>> {
+        let mut hosts = vec![None];
+        for ip in ["172.16.0.1", "0.0.0.0", "255.255.255.255", "::1", "fd00::1", "fe80::1:2:3:4"] {
+            hosts.push(Some(ip.parse().unwrap()));
+        }
+        hosts.push(Some("2001:db8:85a3:0:0:8a2e:370:7334".parse().unwrap()));
+        hosts
+    }
+
+    #[test]
+    fn format_parse_normalize_round_trip() {
+        for host in sample_hosts() {
+            for (display, screen) in [(0, None), (1, Some(0)), (65535, Some(65535))] {
+                for brackets in [true, false] {
+                    let formatted = format_display_brackets(host, display, screen, brackets);
+                    let (parsed_host, parsed_display, parsed_screen) = parsed(&formatted);
+                    assert_eq!(parsed_host.parse().ok(), host, "host of {formatted}");
+                    assert_eq!((parsed_display, parsed_screen), (display, screen), "{formatted}");
+                    let normalized = normalize_display(&formatted).unwrap();
+                    // bare IPv6 addresses get their brackets back
+                    assert_eq!(normalized, format_display(host, display, screen));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        for (s, expected) in [
+            ("172.16.0.1:01", "172.16.0.1:1"),
+            ("172.16.0.1:+1.+0", "172.16.0.1:1.0"),
+            ("fd00:0:0::1:0", "[fd00::1]:0"),
+            ("[FD00:0000::0001]:1.2", "[fd00::1]:1.2"),
+            ("[fe80::0001%eth0]:0", "[fe80::1%eth0]:0"),
+            ("fe80::1%eth0:0", "[fe80::1%eth0]:0"),
+            ("host.example:007", "host.example:7"),
+            (":00", ":0"),
+            ("/tmp/.X11-unix/X0:0.00", "/tmp/.X11-unix/X0:0.0"),
+        ] {
+            assert_eq!(normalize_display(s).unwrap(), expected, "normalizing {s}");
+        }
+    }
+
+    #[test]
+    fn normalize_is_stable() {
+        for s in ["172.16.0.1:01", "fd00:0:0::1:0", "[fe80::0001%eth0]:0", "host:1.1", ":0"] {
+            let normalized = normalize_display(s).unwrap();
+            assert_eq!(normalize_display(&normalized).unwrap(), normalized, "normalizing {s}");
+        }
+    }
+
+    #[test]
+    fn normalize_errors() {
+        for s in ["", "172.16.0.1", "[fd00::1]", "host:x", "bad host:0"] {
+            assert!(normalize_display(s).is_err(), "'{s}' should fail to normalize");
+        }
+    }
+
+    #[test]
+    fn quote_special_characters() {
+        let value = r"a'b\c!d";
+        assert_eq!(shell_quote(Shell::Sh, value), r"'a'\''b\c!d'");
+        assert_eq!(shell_quote(Shell::Fish, value), r"'a\'b\\c!d'");
+        assert_eq!(shell_quote(Shell::Csh, value), r"'a'\''b\c\!d'");
+        assert_eq!(shell_quote(Shell::Csh, "a\nb"), "'a\\\nb'");
+    }
+
+    #[test]
+    fn export_quoted_for_shell() {
+        assert_eq!(format_export(Shell::Sh, "DISPLAY", "it's"), r"export DISPLAY='it'\''s'");
+        assert_eq!(format_export(Shell::Fish, "DISPLAY", r"a\b"), r"set -gx DISPLAY 'a\\b'");
+        assert_eq!(format_export(Shell::Csh, "DISPLAY", "a!b"), r"setenv DISPLAY 'a\!b'");
+    }
+}
@@ -0,0 +1,140 @@
+//! A minimal DNS client for the hostname method, so that resolving the host's name doesn't depend
+//! on the system resolver (which is the thing that's broken when resolv.conf is wrong)
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+/// DNS record types we ask for
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Look up the A and AAAA records for `name` by querying the DNS server at `server` directly over
+/// UDP. Each query waits at most `timeout` for an answer.
+pub fn resolve_with(server: IpAddr, name: &str, timeout: Duration) -> Result<Vec<IpAddr>> {
+    let bind: SocketAddr = match server {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).context("failed to bind UDP socket")?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect((server, 53)).with_context(|| format!("failed to connect to {server}"))?;
+
+    let mut ips = Vec::new();
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        let id = RandomState::new().build_hasher().finish() as u16;
+        socket.send(&build_query(id, name, qtype)?)?;
+        let mut buf = [0u8; 1500];
+        // skip any stray responses that aren't for this query
+        loop {
+            let n = socket.recv(&mut buf).with_context(|| format!("no answer from {server}"))?;
+            match parse_dns_response(&buf[..n], id) {
+                Ok(answers) => {
+                    debug!("{} answered {:?} for {} type {}", server, answers, name, qtype);
+                    ips.extend(answers);
+                    break;
+                }
+                Err(e) if e.is::<WrongId>() => continue,
+                Err(e) => return Err(e.context(format!("bad answer from {server}"))),
+            }
+        }
+    }
+    ensure!(!ips.is_empty(), "{server} has no addresses for {name}");
+    Ok(ips)
+}
+
+/// Build a recursive query for `name` with record type `qtype`
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend(id.to_be_bytes());
+    // flags: recursion desired, then one question and no other records
+    query.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid DNS name '{name}': labels must be 1 to 63 characters"
+        );
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(qtype.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Error for a response that doesn't match the query's ID
+#[derive(Debug)]
+struct WrongId;
+
+impl std::fmt::Display for WrongId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("response ID doesn't match the query")
+    }
+}
+
+impl std::error::Error for WrongId {}
+
+/// Parse a DNS response to the query with `id`, returning the addresses in its A and AAAA answers.
+///
+/// The response comes from the network, so this must never panic no matter what it's given.
+pub fn parse_dns_response(buf: &[u8], id: u16) -> Result<Vec<IpAddr>> {
+    let u16_at = |pos: usize| -> Result<u16> {
+        buf.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| anyhow!("response truncated"))
+    };
+
+    if u16_at(0)? != id {
+        return Err(WrongId.into());
+    }
+    let flags = u16_at(2)?;
+    ensure!(flags & 0x8000 != 0, "not a response");
+    match flags & 0x000f {
+        0 => (),
+        3 => bail!("no such name"),
+        rcode => bail!("server returned error code {rcode}"),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+        let (rtype, class, len) = (u16_at(pos)?, u16_at(pos + 2)?, usize::from(u16_at(pos + 8)?));
+        pos += 10;
+        let data = buf.get(pos..pos + len).ok_or_else(|| anyhow!("response truncated"))?;
+        pos += len;
+        if class != CLASS_IN {
+            continue;
+        }
+        // CNAMEs and such are skipped, recursive servers include the records they point to
+        match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => ips.push(IpAddr::from(octets)),
+            (TYPE_AAAA, _, Ok(octets)) => ips.push(IpAddr::from(octets)),
+            _ => (),
+        }
+    }
+    Ok(ips)
+}
+
+/// Skip over the possibly compressed name starting at `pos`, returning the position after it
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| anyhow!("response truncated"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // a compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + usize::from(len),
+        }
+    }
+}
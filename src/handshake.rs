@@ -0,0 +1,167 @@
+//! The X11 connection setup handshake, for --handshake
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde_json::{json, Value};
+
+use crate::Args;
+#[cfg(feature = "tls")]
+use crate::{tls, ProbeProtocol};
+
+/// What an X server said about itself in its connection setup reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// Whether the server accepted the connection. Servers with access control enabled refuse
+    /// unauthenticated connections, but that still shows they're X servers.
+    pub accepted: bool,
+    /// The X protocol major and minor version
+    pub protocol_version: (u16, u16),
+    /// The vendor's release number, only sent when the connection was accepted
+    pub release: Option<u32>,
+    /// The vendor string, e.g. "The X.Org Foundation", only sent when the connection was accepted
+    pub vendor: Option<String>,
+    /// Why the server refused the connection
+    pub reason: Option<String>,
+}
+
+impl ServerInfo {
+    /// Convert to JSON for --json output
+    pub fn to_json(&self) -> Value {
+        json!({
+            "accepted": self.accepted,
+            "protocol_version": format!("{}.{}", self.protocol_version.0, self.protocol_version.1),
+            "release": self.release,
+            "vendor": self.vendor,
+            "reason": self.reason,
+        })
+    }
+}
+
+/// Connect to `sa`, send an X11 connection setup request with no authorization, and parse the
+/// server's reply. Reading the reply has its own --read-timeout, so a peer that accepts the
+/// connection but never says anything can't hang it.
+pub(crate) fn x_handshake(args: &Args, sa: &SocketAddr) -> Result<ServerInfo> {
+    let mut stream = TcpStream::connect_timeout(sa, args.timeout)
+        .with_context(|| format!("failed to connect to {sa} for the X handshake"))?;
+    setup_handshake(args, &mut stream)
+}
+
+/// Check that the peer of a probe connection is really an X server for --strict-connect, by
+/// going through the connection setup on it. Anything else is an InvalidData error, which counts
+/// as a failed attempt.
+pub(crate) fn verify_x_server(args: &Args, stream: &mut TcpStream) -> io::Result<()> {
+    setup_handshake(args, stream)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("not an X server: {e:#}")))
+}
+
+/// Do the X11 connection setup on a connected `stream`, with no authorization. With
+/// --probe-protocol tls, that happens inside TLS.
+fn setup_handshake(args: &Args, stream: &mut TcpStream) -> Result<ServerInfo> {
+    let read_timeout = args.read_timeout();
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(read_timeout))?;
+    let peer = stream.peer_addr()?;
+    #[cfg(feature = "tls")]
+    if args.probe_protocol == ProbeProtocol::Tls {
+        let mut conn = tls::client(args, peer.ip())?;
+        return exchange_setup(&mut rustls::Stream::new(&mut conn, stream), peer, read_timeout);
+    }
+    exchange_setup(stream, peer, read_timeout)
+}
+
+/// Send the connection setup request on `stream` and read the reply from the X server at `peer`
+fn exchange_setup(
+    stream: &mut (impl Read + Write),
+    peer: SocketAddr,
+    read_timeout: Duration,
+) -> Result<ServerInfo> {
+    // little-endian, protocol 11.0, empty authorization name and data
+    let request = [b'l', 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    stream.write_all(&request).context("failed to send the X connection setup request")?;
+
+    // the fixed 8-byte header says how many 4-byte units follow it
+    let mut reply = vec![0u8; 8];
+    stream.read_exact(&mut reply).map_err(|e| read_error(e, read_timeout))?;
+    let extra = usize::from(u16::from_le_bytes([reply[6], reply[7]])) * 4;
+    reply.resize(8 + extra, 0);
+    stream.read_exact(&mut reply[8..]).map_err(|e| read_error(e, read_timeout))?;
+
+    let info = parse_setup_reply(&reply)?;
+    debug!(
+        "{} X server: accepted: {}, protocol {}.{}, release {:?}, vendor {:?}, reason {:?}",
+        peer,
+        info.accepted,
+        info.protocol_version.0,
+        info.protocol_version.1,
+        info.release,
+        info.vendor,
+        info.reason
+    );
+    Ok(info)
+}
+
+/// Describe an error reading the setup reply, read timeouts show up as WouldBlock
+fn read_error(e: io::Error, read_timeout: Duration) -> anyhow::Error {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            anyhow!("no X connection setup reply within {read_timeout:?}")
+        }
+        ErrorKind::UnexpectedEof => anyhow!("X connection setup reply was cut short"),
+        _ => anyhow::Error::new(e).context("failed to read the X connection setup reply"),
+    }
+}
+
+/// Parse a little-endian X11 connection setup reply.
+///
+/// Every reply starts with a status byte (0 failed, 1 success, 2 authenticate), then a byte that's
+/// the reason length for a failure, the protocol major and minor versions, and the length of the
+/// rest of the reply in 4-byte units. A failure's reason string follows right after that. A
+/// success has 32 bytes of fixed fields, including the release number and the vendor string
+/// length, before the vendor string. An authenticate reply's reason fills the rest of the reply.
+/// This must never panic.
+pub fn parse_setup_reply(reply: &[u8]) -> Result<ServerInfo> {
+    ensure!(reply.len() >= 8, "X setup reply is only {} bytes", reply.len());
+    let u16_at = |i: usize| u16::from_le_bytes([reply[i], reply[i + 1]]);
+    let protocol_version = (u16_at(2), u16_at(4));
+    let extra = usize::from(u16_at(6)) * 4;
+    let body = reply.get(8..8 + extra).context("X setup reply is shorter than its length")?;
+    let text = |start: usize, len: usize| -> Result<String> {
+        let bytes =
+            body.get(start..start + len).context("X setup reply string is out of bounds")?;
+        Ok(String::from_utf8_lossy(bytes).trim_end_matches('\0').to_owned())
+    };
+
+    match reply[0] {
+        0 => Ok(ServerInfo {
+            accepted: false,
+            protocol_version,
+            release: None,
+            vendor: None,
+            reason: Some(text(0, usize::from(reply[1]))?),
+        }),
+        1 => {
+            ensure!(body.len() >= 32, "X setup reply is too short for a successful setup");
+            let release = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let vendor_len = usize::from(u16::from_le_bytes([body[16], body[17]]));
+            Ok(ServerInfo {
+                accepted: true,
+                protocol_version,
+                release: Some(release),
+                vendor: Some(text(32, vendor_len)?),
+                reason: None,
+            })
+        }
+        2 => Ok(ServerInfo {
+            accepted: false,
+            protocol_version,
+            release: None,
+            vendor: None,
+            reason: Some(text(0, body.len())?),
+        }),
+        status => bail!("invalid X setup reply status {status}, this isn't an X server"),
+    }
+}
@@ -0,0 +1,58 @@
+//! The --log-file history of results
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::civil::civil_from_days;
+use crate::{Method, Outcome};
+
+/// Append a line recording this run's result to the log file at `path`, creating it if needed.
+///
+/// Lines are timestamped `key=value` pairs so that they're easy to grep, e.g.
+/// `2026-01-02T03:04:05Z result=found method=route host=172.30.192.1 display=172.30.192.1:1
+/// attempts=1 elapsed_ms=12`
+pub fn append_history(path: &Path, result: &Result<Outcome>, elapsed: Duration) -> Result<()> {
+    let mut line = format_timestamp(SystemTime::now());
+    match result {
+        Ok(outcome) => {
+            let result = if outcome.display.is_some() { "found" } else { "not-found" };
+            let host = outcome.host.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+            line += &format!(
+                " result={} method={} host={} display={} attempts={}",
+                result,
+                outcome.method.map_or("-", Method::name),
+                host,
+                outcome.display.as_deref().unwrap_or("-"),
+                outcome.attempts,
+            );
+        }
+        Err(e) => line += &format!(" result=error error={:?}", format!("{e:#}")),
+    }
+    line += &format!(" elapsed_ms={}\n", elapsed.as_millis());
+
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write log file {}", path.display()))
+}
+
+/// Format a time as an ISO 8601 UTC timestamp like 2026-01-02T03:04:05Z
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let secs_of_day = secs % 86400;
+
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
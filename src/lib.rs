@@ -0,0 +1,73 @@
+//! Find an X server running on the WSL2 host.
+//!
+//! This is the guts of the wsl2-get-display binary, for tools that want to do the same detection
+//! without shelling out to it. The command line [`Args`] double as the options here, and
+//! `Args::default()` matches running the binary with no arguments or environment variables.
+//!
+//! With the `async` feature, [`find_display_async`] does the same thing using tokio.
+
+// must come first so the macros are visible in the other modules
+#[macro_use]
+mod log;
+
+mod args;
+#[cfg(feature = "async")]
+mod async_probe;
+mod cache;
+mod civil;
+mod detect;
+mod display;
+mod dns;
+mod handshake;
+mod history;
+mod lock;
+mod neigh;
+mod probe;
+mod race;
+mod report;
+#[cfg(feature = "tls")]
+mod tls;
+mod xauth;
+
+#[cfg(feature = "tls")]
+pub use args::ProbeProtocol;
+#[cfg(feature = "simulate")]
+pub use args::Simulate;
+pub use args::{
+    Args, AttemptLogLevel, ColorChoice, CompletionsArgs, DisplayList, DualStackPolicy, IpFamily,
+    IpFormat, Method, Mode, ReportArgs, ReportFormat, RetrySchedule, ScanOrder, Shell, Subnet,
+    WatchArgs, XSocket,
+};
+#[cfg(feature = "async")]
+pub use async_probe::find_display_async;
+pub use cache::{
+    cached_host, cached_method, cached_method_order, network_fingerprint, reset_cached_method,
+    save_cached_host, save_cached_method,
+};
+pub use detect::{
+    detect_host_ip, host_candidates, host_ip_from_resolv_conf, host_ip_from_route,
+    host_ip_from_route_get, host_ips_from_resolv_conf, method_available, parse_addr_json,
+    parse_host_file, parse_proc_route, parse_resolv_conf, parse_resolv_conf_nameservers,
+    parse_route_get_json, parse_route_json, read_host_file, resolv_conf_host_ips, running_in_wsl,
+    Candidate, DefaultRoute, DetectFn, DetectionError, Detector, MethodAttempt,
+};
+pub use display::{
+    format_display, format_display_brackets, format_export, format_function, format_ip,
+    format_pulse_server, format_socket_display, normalize_display, parse_display,
+};
+pub use dns::{parse_dns_response, resolve_with};
+pub use handshake::{parse_setup_reply, ServerInfo};
+pub use history::append_history;
+pub use lock::find_display_locked;
+pub use log::{
+    emit_warning, error_label, set_color, set_strict, set_verbosity, take_warnings, warning_label,
+    Warning,
+};
+pub use neigh::{neighbor, parse_neigh_json, parse_proc_arp, Neighbor};
+pub use probe::{
+    find_display, local_displays, probe_latencies, Attempt, ConnectError, HostRefused, Outcome,
+    DISPLAY_PORT_OFFSET, PULSE_PORT,
+};
+pub use race::find_display_parallel;
+pub use report::{detection_report, execution_plan, report_to_toml};
+pub use xauth::{parse_xauthority, xauth_displays};
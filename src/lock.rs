@@ -0,0 +1,201 @@
+//! Lock file to serialize detection between concurrent processes
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::{find_display, parse_display, Args, Method, MethodAttempt, Outcome, ServerInfo};
+
+/// How long to wait for another process holding the `--lock` file before giving up and running
+/// detection independently
+const LOCK_WAIT: Duration = Duration::from_secs(2);
+
+/// How often to poll the `--lock` file while waiting for it
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Open and lock `path`, waiting up to LOCK_WAIT for any other process to release it.
+///
+/// Returns the locked file and whether we had to wait for it, or None if the wait timed out.
+fn acquire_lock(path: &Path) -> Result<Option<(File, bool)>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("failed to open lock file {}", path.display()))?;
+
+    let start = Instant::now();
+    let mut waited = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(Some((file, waited))),
+            Err(TryLockError::WouldBlock) if start.elapsed() < LOCK_WAIT => {
+                waited = true;
+                sleep(LOCK_POLL_INTERVAL);
+            }
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("failed to lock {}", path.display()))
+            }
+        }
+    }
+}
+
+/// A hash of every option in `args`, so that a result saved in the lock file is only reused by a
+/// process looking for the same thing. Like the --cache fingerprint, it only has to match itself.
+fn args_fingerprint(args: &Args) -> String {
+    let mut hasher = DefaultHasher::new();
+    format!("{args:?}").hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// The line to save in the lock file for other processes run with `args` to reuse `outcome`
+fn saved_result(args: &Args, outcome: &Outcome) -> String {
+    json!({ "args": args_fingerprint(args), "outcome": outcome.to_json() }).to_string()
+}
+
+/// Get the outcome from a saved_result() line, if it was saved with the same `args` and found an
+/// X server. It didn't make any connection attempts of its own.
+fn reuse_saved(args: &Args, saved: &str) -> Option<Outcome> {
+    let saved: Value = serde_json::from_str(saved)
+        .map_err(|e| debug!("ignoring invalid lock file result: {}", e))
+        .ok()?;
+    if saved["args"] != args_fingerprint(args).as_str() {
+        debug!("lock file result was saved with different options, not reusing it");
+        return None;
+    }
+    let js = &saved["outcome"];
+    let display = js["display"].as_str()?;
+    parse_display(display)
+        .map_err(|e| debug!("ignoring invalid lock file result: {:#}", e))
+        .ok()?;
+    let string = |key: &str| js[key].as_str().map(str::to_owned);
+    Some(Outcome {
+        display: Some(display.to_owned()),
+        host: js["host"].as_str().and_then(|host| host.parse().ok()),
+        method: js["method"].as_str().and_then(|name| Method::from_str(name, false).ok()),
+        method_log: js["methods"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(method_attempt)
+            .collect(),
+        host_reachable: js["host_reachable"].as_bool(),
+        server: js.get("server").and_then(server_info),
+        pulse_server: string("pulse_server"),
+        other_display: string("other_display"),
+        ..Outcome::default()
+    })
+}
+
+/// Read back a MethodAttempt::to_json() object
+fn method_attempt(js: &Value) -> Option<MethodAttempt> {
+    Some(MethodAttempt {
+        method: Method::from_str(js["method"].as_str()?, false).ok()?,
+        ip: js["ip"].as_str().and_then(|ip| ip.parse().ok()),
+        error: js["error"].as_str().map(str::to_owned),
+    })
+}
+
+/// Read back a ServerInfo::to_json() object
+fn server_info(js: &Value) -> Option<ServerInfo> {
+    let (major, minor) = js["protocol_version"].as_str()?.split_once('.')?;
+    Some(ServerInfo {
+        accepted: js["accepted"].as_bool()?,
+        protocol_version: (major.parse().ok()?, minor.parse().ok()?),
+        release: js["release"].as_u64().and_then(|release| release.try_into().ok()),
+        vendor: js["vendor"].as_str().map(str::to_owned),
+        reason: js["reason"].as_str().map(str::to_owned),
+    })
+}
+
+/// Run find_display() while holding the lock file at `path`, or reuse the result that another
+/// process run with the same options saved there while we waited for the lock.
+pub fn find_display_locked(args: &Args, path: &Path) -> Result<Outcome> {
+    let Some((mut file, waited)) = acquire_lock(path)? else {
+        debug!("timed out waiting for lock {}, running detection anyway", path.display());
+        return find_display(args);
+    };
+
+    if waited {
+        let mut saved = String::new();
+        file.read_to_string(&mut saved)
+            .with_context(|| format!("failed to read lock file {}", path.display()))?;
+        let saved = saved.trim();
+        if saved.is_empty() {
+            // the other process found nothing or failed, try for ourselves
+            debug!("lock file {} is empty, running detection", path.display());
+        } else if let Some(outcome) = reuse_saved(args, saved) {
+            debug!("reusing result from lock file {}", path.display());
+            return Ok(outcome);
+        }
+    }
+
+    // clear out any old result first, so a failure below doesn't leave it for other processes.
+    // Reading it above moved the file position, which would leave a hole of NULs before our result.
+    file.set_len(0).with_context(|| format!("failed to truncate lock file {}", path.display()))?;
+    file.rewind().with_context(|| format!("failed to rewind lock file {}", path.display()))?;
+    let outcome = find_display(args)?;
+    if outcome.display.is_some() {
+        writeln!(file, "{}", saved_result(args, &outcome))
+            .with_context(|| format!("failed to write lock file {}", path.display()))?;
+    }
+    Ok(outcome)
+    // file goes out of scope and is closed, releasing the lock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::parse_from([env!("CARGO_PKG_NAME")].iter().chain(args))
+    }
+
+    #[test]
+    fn reuse_whole_outcome() {
+        let outcome = Outcome {
+            display: Some("172.16.0.1:1".to_owned()),
+            host: "172.16.0.1".parse().ok(),
+            method: Some(Method::ResolvConf),
+            method_log: vec![MethodAttempt {
+                method: Method::ResolvConf,
+                ip: "172.16.0.1".parse().ok(),
+                error: None,
+            }],
+            server: Some(ServerInfo {
+                accepted: true,
+                protocol_version: (11, 0),
+                release: Some(12101004),
+                vendor: Some("The X.Org Foundation".to_owned()),
+                reason: None,
+            }),
+            pulse_server: Some("tcp:172.16.0.1:4713".to_owned()),
+            other_display: Some("[fd00::1]:1".to_owned()),
+            ..Outcome::default()
+        };
+        let args = parse(&["--handshake", "--pulse"]);
+        assert_eq!(reuse_saved(&args, &saved_result(&args, &outcome)), Some(outcome));
+    }
+
+    #[test]
+    fn other_options_not_reused() {
+        let outcome = Outcome { display: Some("172.16.0.1:1".to_owned()), ..Outcome::default() };
+        let saved = saved_result(&parse(&["1"]), &outcome);
+        assert_eq!(reuse_saved(&parse(&["1"]), &saved), Some(outcome));
+        for args in [&["2"][..], &["--scan", "0..4"], &["--pulse"], &["--handshake"]] {
+            assert_eq!(reuse_saved(&parse(args), &saved), None, "{args:?}");
+        }
+        assert_eq!(reuse_saved(&parse(&["1"]), "172.16.0.1:1"), None);
+    }
+}
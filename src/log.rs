@@ -0,0 +1,120 @@
+//! Lazy global-variable logging and warning macros
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// Lazy global-variable debug logging, the value is the number of times -v was given
+pub(crate) static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Whether warnings should be treated as errors, set by --strict
+pub(crate) static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Whether warning and error labels are colored, set by --color
+pub(crate) static COLOR: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Whether the connection attempt being made on this thread is logged, for
+    /// --attempts-log-level. Probes run sequentially on each thread, --parallel or not.
+    pub(crate) static ATTEMPT_LOGGED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Warnings emitted so far, until they're taken with take_warnings()
+static WARNINGS: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+/// A problem that didn't stop detection, like a failed method or an ignored candidate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Record a warning. Nothing is printed here, callers collect them with take_warnings() once
+/// they're done and decide how to show them.
+pub fn emit_warning(message: impl Into<String>) {
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    warnings.push(Warning { message: message.into() });
+}
+
+/// Take every warning recorded since the last call
+pub fn take_warnings() -> Vec<Warning> {
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    std::mem::take(&mut *warnings)
+}
+
+/// Set the debug logging level, 0 is off
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Set whether warnings are returned as errors
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+/// Set whether warning and error labels are colored
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Color `label` with the ANSI SGR `code` if color is enabled
+fn paint(label: &str, code: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{code}m{label}\x1b[0m")
+    } else {
+        label.to_owned()
+    }
+}
+
+/// The "warning:" label that starts warning messages, yellow with --color
+pub fn warning_label() -> String {
+    paint("warning:", "1;33")
+}
+
+/// The "Error:" label that starts the fatal error message, red with --color
+pub fn error_label() -> String {
+    paint("Error:", "1;31")
+}
+
+macro_rules! debug {
+    ($($args:tt)+) => {
+        if $crate::log::VERBOSITY.load(::std::sync::atomic::Ordering::Relaxed) >= 1 {
+            eprintln!($($args)+);
+        }
+    };
+}
+
+/// Debug logging for a single connection attempt, unless --attempts-log-level leaves it out
+macro_rules! attempt_debug {
+    ($($args:tt)+) => {
+        if $crate::log::ATTEMPT_LOGGED.get() {
+            debug!($($args)+);
+        }
+    };
+}
+
+/// Extra-verbose logging for things like raw file contents, enabled by -vv
+macro_rules! trace {
+    ($($args:tt)+) => {
+        if $crate::log::VERBOSITY.load(::std::sync::atomic::Ordering::Relaxed) >= 2 {
+            eprintln!($($args)+);
+        }
+    };
+}
+
+/// Record a warning with emit_warning(), or return it as an error from the current function in
+/// strict mode
+macro_rules! warning {
+    ($($args:tt)+) => {
+        if $crate::log::STRICT.load(::std::sync::atomic::Ordering::Relaxed) {
+            ::anyhow::bail!($($args)+);
+        }
+        $crate::log::emit_warning(format!($($args)+));
+    };
+}
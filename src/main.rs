@@ -3,188 +3,983 @@
 // But since I gotta shell out to another binary anyway, I might as well add the /etc/resolv.conf
 // parsing logic here too.
 
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{Cursor, ErrorKind};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::OnceLock;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
-use clap::Parser;
-use serde_json::{self, Value};
+use anyhow::{bail, ensure, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use inotify::{Inotify, WatchMask};
+use serde_json::{json, Value};
 
-/// X11 port number is 6000 plus the display number
-const DISPLAY_PORT_OFFSET: u16 = 6000;
+#[cfg(feature = "tls")]
+use wsl2_get_display::ProbeProtocol;
+use wsl2_get_display::{
+    append_history, cached_host, cached_method, cached_method_order, detect_host_ip,
+    detection_report, emit_warning, error_label, execution_plan, find_display, find_display_locked,
+    format_export, format_function, format_ip, host_candidates, method_available, parse_display,
+    probe_latencies, report_to_toml, reset_cached_method, save_cached_host, save_cached_method,
+    set_color, set_strict, set_verbosity, take_warnings, warning_label, xauth_displays, Args,
+    ColorChoice, CompletionsArgs, ConnectError, DetectionError, DisplayList, HostRefused, Method,
+    Mode, Outcome, ReportFormat, ScanOrder, DISPLAY_PORT_OFFSET,
+};
 
-/// Lazy global-variable debug logging
-static DEBUG: AtomicBool = AtomicBool::new(false);
-macro_rules! debug {
-    ($($args:tt),+) => {
-        if DEBUG.load(Ordering::Relaxed) {
-            eprintln!($($args),+);
+/// Like println!, but returns write errors instead of panicking so that a closed pipe (e.g. piping
+/// into `head -1`) can be handled in main
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        writeln!(io::stdout().lock(), $($arg)*)
+    };
+}
+
+/// Build the --version-json output from the metadata captured by build.rs
+fn version_json() -> Value {
+    let commit = env!("BUILD_GIT_COMMIT");
+    let features: Vec<&str> = env!("BUILD_FEATURES").split(',').filter(|f| !f.is_empty()).collect();
+    json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": if commit.is_empty() { Value::Null } else { commit.into() },
+        "build_date": env!("BUILD_DATE"),
+        "target": env!("BUILD_TARGET"),
+        "profile": env!("BUILD_PROFILE"),
+        "features": features,
+    })
+}
+
+/// Format the lines to print for `outcome`, None if there's nothing to print
+fn format_output(args: &Args, outcome: &Outcome) -> Option<String> {
+    if args.probe_only {
+        // exit status only
+        None
+    } else if args.json || args.json_pretty || args.json_verbose {
+        let mut js = if args.json_verbose { outcome.to_json_verbose() } else { outcome.to_json() };
+        // structured rather than on stderr
+        let warnings: Vec<String> = take_warnings().iter().map(ToString::to_string).collect();
+        if !warnings.is_empty() {
+            js["warnings"] = warnings.into();
+        }
+        Some(if args.json_pretty { format!("{:#}", js) } else { js.to_string() })
+    } else if args.count_only {
+        Some(outcome.attempts.to_string())
+    } else if args.icon {
+        let number = outcome.display.as_deref().and_then(|display| parse_display(display).ok());
+        Some(match number {
+            Some((_, number, _)) => format!("{}:{}", args.icon_found, number),
+            None => args.icon_missing.clone(),
+        })
+    } else {
+        let variable = |name: &str, value: &str| {
+            if args.export {
+                format_export(args.shell, name, value)
+            } else if args.systemd_env {
+                format!("{}={}", name, value)
+            } else {
+                value.to_owned()
+            }
+        };
+        let variables = [
+            ("DISPLAY", &outcome.display),
+            ("OTHER_DISPLAY", &outcome.other_display),
+            ("PULSE_SERVER", &outcome.pulse_server),
+        ];
+        if let Some(command) = &args.exec_form {
+            outcome.display.as_ref()?;
+            let assignments = variables
+                .into_iter()
+                .filter_map(|(name, value)| Some(format!("{}={}", name, value.as_deref()?)));
+            let prefix = format!("env {}", assignments.collect::<Vec<_>>().join(" "));
+            return Some(if command.is_empty() {
+                prefix
+            } else {
+                format!("wsl.exe -- {prefix} {command}")
+            });
+        }
+        let lines: Vec<String> = variables
+            .into_iter()
+            .filter_map(|(name, value)| Some(variable(name, value.as_deref()?)))
+            .collect();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+}
+
+/// Replace the file at `path` with `output`, for --write. The new contents are written to a
+/// temporary file that's renamed over it, so readers never see a partial write.
+fn write_atomic(path: &Path, output: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp{}", std::process::id()));
+    fs::write(&tmp, format!("{output}\n"))
+        .and_then(|()| fs::rename(&tmp, path))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Run detection every `interval` forever for --watch, printing the output (and writing it to the
+/// --fifo) each time it changes. Errors are reported but don't stop watching.
+fn watch(args: &Args, interval: Duration) -> Result<bool> {
+    if let Some(path) = &args.fifo {
+        create_fifo(path)?;
+    }
+    let resolv_conf_changes = watch_resolv_conf();
+    let mut last = None;
+    loop {
+        let output = match find_display(args) {
+            Ok(outcome) => format_output(args, &outcome).unwrap_or_default(),
+            Err(e) => {
+                eprintln!("{} {:#}", error_label(), e);
+                String::new()
+            }
+        };
+        for warning in take_warnings() {
+            eprintln!("{} {}", warning_label(), warning);
+        }
+        // an empty line means the X server went away, but there's no need to start with one
+        if last.as_ref() != Some(&output) && (last.is_some() || !output.is_empty()) {
+            outln!("{}", output)?;
+            if let Some(path) = &args.fifo {
+                if let Err(e) = write_fifo(path, &output) {
+                    if args.verbose > 0 {
+                        eprintln!("not writing to {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        last = Some(output);
+        match &resolv_conf_changes {
+            Some(changes) => {
+                if changes.recv_timeout(interval).is_ok() {
+                    // WSL can write the file several times in a row, only react once
+                    sleep(RESOLV_CONF_SETTLE);
+                    while changes.try_recv().is_ok() {}
+                    if args.verbose > 0 {
+                        eprintln!("/etc/resolv.conf changed, redoing detection");
+                    }
+                }
+            }
+            None => sleep(interval),
+        }
+    }
+}
+
+/// How long to wait after /etc/resolv.conf changes before --watch redoes detection
+const RESOLV_CONF_SETTLE: Duration = Duration::from_millis(100);
+
+/// Watch /etc/resolv.conf with inotify for --watch, returning a channel that gets a message every
+/// time it changes. The directories are watched rather than the file, so that it's still seen
+/// after being replaced by a rename, and with a symlink both the link and its target are watched.
+/// Without inotify this is None, and --watch only polls.
+fn watch_resolv_conf() -> Option<Receiver<()>> {
+    let link = Path::new("/etc/resolv.conf");
+    let target = fs::canonicalize(link).unwrap_or_else(|_| link.to_owned());
+    let mut files: Vec<(PathBuf, OsString)> = Vec::new();
+    for path in [link, &target] {
+        if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+            if !files.iter().any(|(d, n)| d == dir && n == name) {
+                files.push((dir.to_owned(), name.to_owned()));
+            }
+        }
+    }
+
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            emit_warning(format!("can't watch /etc/resolv.conf, only polling: {e}"));
+            return None;
         }
     };
+    let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE;
+    let mut watches = Vec::new();
+    for (dir, name) in files {
+        match inotify.watches().add(&dir, mask) {
+            Ok(wd) => watches.push((wd, name)),
+            Err(e) => emit_warning(format!("can't watch {}: {e}", dir.display())),
+        }
+    }
+    if watches.is_empty() {
+        return None;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("{} stopped watching /etc/resolv.conf: {}", warning_label(), e);
+                    return;
+                }
+            };
+            let changed = events.into_iter().any(|event| {
+                watches.iter().any(|(wd, name)| event.wd == *wd && event.name == Some(name))
+            });
+            // the receiver going away means main is exiting
+            if changed && tx.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    Some(rx)
 }
 
-/// Determine the host/hypervisor IP by reading the first nameserver from /etc/resolv.conf
-///
-/// This is what most basic answers/tutorials online suggest, and it's fine in a default
-/// configuration, but won't work in WSL setups that use a custom resolv.conf (e.g. when needing to
-/// add search domains or something, or for any other reason don't use the host as WSL's DNS)
-fn host_ip_from_resolv_conf() -> Result<IpAddr> {
-    String::from_utf8(fs::read("/etc/resolv.conf").context("failed to read /etc/resolv.conf")?)
-        .context("/etc/resolv.conf isn't valid utf8")?
-        .lines()
-        .find_map(|line| {
-            let mut words = line.split_ascii_whitespace();
-            match (words.next(), words.next()) {
-                (Some("nameserver"), Some(addr)) => Some(addr.to_owned()),
-                (_, _) => None,
+/// Create a FIFO at `path` with mkfifo, unless one's already there
+fn create_fifo(path: &Path) -> Result<()> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_fifo() => return Ok(()),
+        Ok(_) => bail!("{} exists and isn't a FIFO", path.display()),
+        Err(_) => (),
+    }
+    let status = Command::new("mkfifo")
+        .arg(path)
+        .status()
+        .with_context(|| format!("failed to run mkfifo {}", path.display()))?;
+    ensure!(status.success(), "mkfifo {} failed", path.display());
+    Ok(())
+}
+
+/// Write a line to the --fifo for a reader to pick up. The FIFO is opened without blocking, so
+/// when nobody is reading, or the reader goes away partway through, the update is just skipped.
+fn write_fifo(path: &Path, line: &str) -> io::Result<()> {
+    // with O_NONBLOCK, opening a FIFO for writing fails with ENXIO rather than waiting for a reader
+    let mut fifo = fs::OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+    writeln!(fifo, "{line}")
+}
+
+/// How long --serve waits on a client that isn't reading its reply, and --query waits for the
+/// server, which may have to do detection with retries first
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer clients on the Unix socket at `path` forever for --serve. Detection happens when a
+/// client connects and there's no successful result kept from before.
+fn serve(args: &Args, path: &Path) -> Result<bool> {
+    let listener = listen_unix(path)?;
+    let mut output: Option<String> = None;
+    for client in listener.incoming() {
+        let mut client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{} failed to accept a client: {}", error_label(), e);
+                continue;
             }
-        })
-        .ok_or_else(|| anyhow!("unable to find host IP address in /etc/resolv.conf"))?
-        .parse::<IpAddr>()
-        .context("unable to parse host IP address")
-}
-
-/// Determine the host/hypervisor IP by getting the default IPv4 route.
-///
-/// This parses `ip -4 -json route show default` and extracts the gateway IP address. It should be
-/// more reliable than the /etc/resolv.conf method, but could still fail in case a VPN client is
-/// running inside the WSL VM or something like that.
-///
-/// Tested on iproute2 v5.9.0 on ubuntu 21.10. I think the json flag was added in v4.17 which was
-/// released in mid-2018, so a somewhat recent distro is needed.
-fn host_ip_from_route() -> Result<IpAddr> {
-    let mut cmd = Command::new("ip");
-    cmd.args(["-4", "-json", "route", "show", "default"])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit());
-    let output = cmd.output().context("failed to execute {cmd:?}")?;
-    if !output.status.success() {
-        bail!("command {cmd:?} failed");
-    }
-
-    // The JSON output should like this. Parse manually rather than pulling in serde derive macros.
-    // [
-    //   {
-    //     "dst": "default",
-    //     "gateway": "172.30.192.1",
-    //     "dev": "eth0",
-    //     "flags": []
-    //   }
-    // ]
-    let js: Value = serde_json::from_reader(Cursor::new(&output.stdout))
-        .context("failed to parse output as JSON")?;
-    debug!("{cmd:?} returned parsed data:\n{js:#?}");
-
-    // unwrap inner object out of outer array
-    let js = match js {
-        Value::Array(mut values) => {
-            if values.is_empty() {
-                bail!("empty json array");
-            } else if values.len() > 1 {
-                eprintln!("warning: ip route returned multiple defaults routes: {values:?}");
+        };
+        if output.is_none() {
+            output = match find_display(args) {
+                Ok(outcome) if outcome.display.is_some() => format_output(args, &outcome),
+                Ok(_) => None,
+                Err(e) => {
+                    eprintln!("{} {:#}", error_label(), e);
+                    None
+                }
+            };
+            for warning in take_warnings() {
+                eprintln!("{} {}", warning_label(), warning);
             }
-            values.remove(0)
         }
-        not_an_array => bail!("expected JSON array, got {not_an_array}"),
+        let reply = output.as_ref().map(|output| format!("{output}\n")).unwrap_or_default();
+        // a client hanging up early only matters to that client
+        let result = client
+            .set_write_timeout(Some(CLIENT_TIMEOUT))
+            .and_then(|()| client.write_all(reply.as_bytes()));
+        if let (Err(e), true) = (result, args.verbose > 0) {
+            eprintln!("client went away: {}", e);
+        }
+    }
+    Ok(output.is_some())
+}
+
+/// Listen on a Unix socket at `path`, replacing a stale socket left there by a server that's gone
+fn listen_unix(path: &Path) -> Result<UnixListener> {
+    let context = || format!("failed to listen on {}", path.display());
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            let is_socket = fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+            ensure!(is_socket, "{} exists and isn't a socket", path.display());
+            ensure!(
+                UnixStream::connect(path).is_err(),
+                "another server is already listening on {}",
+                path.display()
+            );
+            fs::remove_file(path).with_context(context)?;
+            UnixListener::bind(path).with_context(context)
+        }
+        result => result.with_context(context),
+    }
+}
+
+/// Print the output of the --serve server at `path`, returning whether it found an X server
+fn query(path: &Path) -> Result<bool> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("failed to connect to {}", path.display()))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .with_context(|| format!("failed to read from {}", path.display()))?;
+    write!(io::stdout().lock(), "{reply}")?;
+    Ok(!reply.is_empty())
+}
+
+/// Measure and print the connection latency to the X server found in `outcome`, for --probe-count
+fn print_latencies(args: &Args, outcome: &Outcome, count: u16) -> Result<bool> {
+    let (Some(host), Some(display)) = (outcome.host, &outcome.display) else {
+        return Ok(false);
+    };
+    let (_, display_number, _) = parse_display(display)?;
+    let latencies = probe_latencies(args, host, display_number, count)?;
+    let ms = |latency: &Duration| latency.as_secs_f64() * 1000.0;
+    let (Some(min), Some(max)) = (latencies.first(), latencies.last()) else {
+        outln!("{display}: 0/{count} connected")?;
+        return Ok(false);
     };
+    outln!(
+        "{display}: {}/{count} connected, min {:.2}ms, median {:.2}ms, max {:.2}ms",
+        latencies.len(),
+        ms(min),
+        ms(&latencies[latencies.len() / 2]),
+        ms(max)
+    )?;
+    Ok(true)
+}
 
-    // sanity check, "dst" field should be "default"
+/// Ask for the host IP on the terminal until one has an X server, for --interactive. Returns None
+/// once an empty line or end of file says to give up.
+fn prompt_for_host(args: &Args) -> Result<Option<Outcome>> {
+    let mut args = args.clone();
+    loop {
+        eprint!("Enter the WSL host IP: ");
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 || line.trim().is_empty() {
+            return Ok(None);
+        }
+        let ip: IpAddr = match line.trim().parse() {
+            Ok(ip) => ip,
+            Err(e) => {
+                eprintln!("invalid IP address: {e}");
+                continue;
+            }
+        };
+        args.host = Some(ip);
+        match find_display(&args) {
+            Ok(outcome) if outcome.display.is_some() => {
+                if let Some(path) = &args.cache {
+                    if let Err(e) = save_cached_host(path, ip) {
+                        emit_warning(format!("{e:#}"));
+                    }
+                }
+                return Ok(Some(outcome));
+            }
+            Ok(_) => eprintln!("no X server found at {ip}"),
+            Err(e) => eprintln!("{} {:#}", error_label(), e),
+        }
+    }
+}
+
+/// Read the host IP, or a whole `host:display`, from the first non-blank line of stdin and set
+/// them in `args`, for --host-from-stdin
+fn read_host_from_stdin(args: &mut Args) -> Result<()> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input).context("failed to read the host from stdin")?;
+    let line = input
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .context("nothing on stdin for --host-from-stdin, expected a host IP address")?;
+    let ip = match line.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            let (host, display, screen) = parse_display(line).with_context(|| {
+                format!("expected a host IP address or host:display on stdin, got '{line}'")
+            })?;
+            args.display_number = display;
+            args.scan = None;
+            args.display_offset = None;
+            if screen.is_some() {
+                args.screen = screen;
+            }
+            host.parse().with_context(|| {
+                format!("the host in display '{line}' from stdin isn't an IP address")
+            })?
+        }
+    };
+    if args.verbose > 0 {
+        eprintln!("using the host IP {ip} from stdin");
+    }
+    args.host = Some(ip);
+    Ok(())
+}
+
+/// The most displays --bitmask can report on, one per bit of the exit status
+const BITMASK_DISPLAYS: usize = 8;
+
+/// Probe each --scan display on its own for --bitmask, returning the exit status: bit N is set if
+/// the Nth display in the --scan list has an X server. The host is only detected once.
+fn display_bitmask(args: &Args) -> Result<u8> {
+    let mut args = args.clone();
+    args.scan_order = ScanOrder::Given;
+    let displays = args.requested_displays();
     ensure!(
-        matches!(js["dst"].as_str(), Some("default")),
-        "route destination is not 'default': {js}"
+        displays.len() <= BITMASK_DISPLAYS,
+        "--bitmask can report on at most {BITMASK_DISPLAYS} displays, but --scan has {}",
+        displays.len()
     );
+    args.scan = None;
+    if args.host.is_none() && args.host_file.is_none() && !args.local && args.unix_socket.is_none()
+    {
+        args.host = Some(detect_host_ip(&args)?.0);
+    }
+    let mut mask = 0;
+    for (bit, display) in displays.into_iter().enumerate() {
+        args.display_number = display;
+        let found = find_display(&args)?.display.is_some();
+        if args.verbose > 0 {
+            eprintln!(
+                "display {display} (bit {bit}): {}",
+                if found { "found" } else { "not found" }
+            );
+        }
+        if found {
+            mask |= 1 << bit;
+        }
+    }
+    Ok(mask)
+}
+
+/// Fill in a --message template with the results of a successful run
+fn format_message(template: &str, outcome: &Outcome) -> String {
+    template
+        .replace("{host}", &outcome.host.map_or_else(|| "-".to_owned(), |ip| ip.to_string()))
+        .replace("{display}", outcome.display.as_deref().unwrap_or("-"))
+        .replace("{method}", outcome.method.map_or("-", Method::name))
+        .replace("{attempts}", &outcome.attempts.to_string())
+}
+
+/// Set DISPLAY in the systemd user manager's environment, for --apply-systemd
+fn apply_systemd_env(display: &str) -> Result<()> {
+    let mut cmd = Command::new("systemctl");
+    cmd.args(["--user", "set-environment", &format!("DISPLAY={display}")]);
+    // keep the error messages predictable
+    cmd.env("LC_ALL", "C").env("LANG", "C");
+    let status = cmd.status().with_context(|| format!("failed to execute {cmd:?}"))?;
+    ensure!(status.success(), "{cmd:?} failed, is the systemd user instance running?");
+    Ok(())
+}
+
+/// The environment.d drop-in that --apply-environment-d writes by default, relative to the user's
+/// config directory
+const ENVIRONMENT_D_FILE: &str = "environment.d/wsl-display.conf";
+
+/// Get the file for --apply-environment-d: `path`, or ENVIRONMENT_D_FILE in $XDG_CONFIG_HOME or
+/// ~/.config if it's empty
+fn environment_d_path(path: &Path) -> Result<PathBuf> {
+    if !path.as_os_str().is_empty() {
+        return Ok(path.to_owned());
+    }
+    let config = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".config"))
+            .context("neither XDG_CONFIG_HOME nor HOME is set")?,
+    };
+    Ok(config.join(ENVIRONMENT_D_FILE))
+}
+
+/// The DISPLAY, and the OTHER_DISPLAY and PULSE_SERVER if there are any, as NAME=value lines
+fn variable_lines(outcome: &Outcome) -> String {
+    let variables = [
+        ("DISPLAY", &outcome.display),
+        ("OTHER_DISPLAY", &outcome.other_display),
+        ("PULSE_SERVER", &outcome.pulse_server),
+    ];
+    let lines: Vec<String> = variables
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{}={}", name, value.as_deref()?)))
+        .collect();
+    lines.join("\n")
+}
+
+/// Open the file descriptor `fd` inherited from the parent for --results-fd. It's reopened through
+/// /proc, which checks that it can be written to without taking ownership of the descriptor.
+fn open_results_fd(fd: i32) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .append(true)
+        .open(format!("/proc/self/fd/{fd}"))
+        .with_context(|| format!("can't write to --results-fd {fd}, is it open?"))
+}
+
+/// Write the variable_lines() to the environment.d drop-in at `path` for --apply-environment-d,
+/// creating its directory if needed. Returns whether the file changed, it's left alone when it
+/// already has the same contents.
+fn apply_environment_d(path: &Path, outcome: &Outcome) -> Result<bool> {
+    let contents = variable_lines(outcome);
+    if fs::read_to_string(path).is_ok_and(|old| old.trim_end() == contents) {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    write_atomic(path, &contents)?;
+    Ok(true)
+}
+
+/// Where WSL registers its binfmt_misc handler for Windows executables. It's missing or disabled
+/// when interop is turned off in /etc/wsl.conf.
+const WSL_INTEROP_BINFMT: &str = "/proc/sys/fs/binfmt_misc/WSLInterop";
+
+/// Where to find setx.exe when the Windows PATH isn't appended to the WSL one
+const SETX_FALLBACK: &str = "/mnt/c/Windows/System32/setx.exe";
+
+/// Set the Windows user environment variable `name` to `display` with setx.exe, for
+/// --apply-windows. setx writes the registry quickly but then waits for every open window to
+/// handle the settings change broadcast, so it's only waited for up to `timeout`.
+fn apply_windows_env(name: &str, display: &str, timeout: Duration) -> Result<()> {
+    match fs::read_to_string(WSL_INTEROP_BINFMT) {
+        Ok(status) => ensure!(
+            status.lines().next() == Some("enabled"),
+            "WSL interop is disabled, can't run setx.exe to set {name} on Windows"
+        ),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!(
+                "WSL interop isn't available ({WSL_INTEROP_BINFMT} is missing), can't run setx.exe"
+            )
+        }
+        // something odd about binfmt_misc, let running setx.exe decide
+        Err(_) => (),
+    }
+    let setx = env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .map(|dir| dir.join("setx.exe"))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(SETX_FALLBACK));
+    let mut cmd = Command::new(&setx);
+    cmd.args([name, display]).stdin(Stdio::null()).stdout(Stdio::null());
+    let mut child = cmd.spawn().with_context(|| format!("failed to execute {}", setx.display()))?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            ensure!(status.success(), "{cmd:?} failed with {status}");
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            // the variable is most likely set already, only the broadcast is still going
+            emit_warning(format!(
+                "setx.exe is still running after {timeout:?}, leaving it to finish in the \
+                 background"
+            ));
+            return Ok(());
+        }
+        sleep(Duration::from_millis(20));
+    }
+}
+
+/// Prints the time elapsed since it was created when dropped, for --timing
+struct Timing(Instant);
+
+impl Drop for Timing {
+    fn drop(&mut self) {
+        eprintln!("elapsed: {:.1}ms", self.0.elapsed().as_secs_f64() * 1000.0);
+    }
+}
+
+/// Decide whether to color stderr for --color
+fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // https://no-color.org says any non-empty value disables color
+        ColorChoice::Auto => {
+            env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stderr().is_terminal()
+        }
+    }
+}
+
+/// Describe where the --method list came from, for --print-method
+fn method_source(args: &Args, matches: &ArgMatches) -> &'static str {
+    if args.resolv_conf {
+        return "--resolv-conf";
+    }
+    match matches.value_source("method") {
+        Some(ValueSource::CommandLine) => "--method",
+        Some(ValueSource::EnvVariable) => "WSL2_DISPLAY_METHOD",
+        _ => "default",
+    }
+}
+
+/// The command line for the --emit-function function to run: this program, with the same
+/// arguments as `argv` (after expanding response files) but --export rather than --emit-function
+fn function_command(argv: &[OsString], export: bool) -> Result<Vec<String>> {
+    let exe = env::current_exe().context("failed to find the path of this program")?;
+    let utf8 = |arg: &OsStr| {
+        arg.to_str().map(str::to_owned).with_context(|| {
+            format!("--emit-function can't put the non-UTF-8 argument {arg:?} in a shell function")
+        })
+    };
+    let mut command = vec![utf8(exe.as_os_str())?];
+    for arg in argv.iter().skip(1).filter(|&arg| arg != "--emit-function") {
+        command.push(utf8(arg)?);
+    }
+    if !export {
+        command.push("--export".to_owned());
+    }
+    Ok(command)
+}
 
-    // extract and parse the gateway field as an IP
-    js["gateway"]
-        .as_str()
-        .ok_or_else(|| anyhow!("default gateway not found (or is not a string)"))?
-        .parse::<IpAddr>()
-        .context("failed to parse default gateway IP address")
-}
-
-/// Find an X server running on the WSL2 host.
-///
-/// wsl-get-display will infer the WSL2 hypervisor IP by finding the first nameserver in
-/// /etc/resolv.conf, then attempts a TCP connection on the appropriate port (6000
-/// + display_number)
-#[derive(Debug, Parser)]
-#[command(version, max_term_width = 80)]
-struct Args {
-    /// Connection timeout in milliseconds
-    #[arg(short, long, default_value = "500")]
-    #[arg(value_parser = |s: &str| s.parse().map(Duration::from_millis))]
-    timeout: Duration,
-
-    /// Number of retries
-    #[arg(short, long, default_value = "1")]
-    retries: u16,
-
-    /// Enables verbose debug output on stderr
-    #[arg(short, long)]
-    verbose: bool,
-
-    /// X display number, e.g. the "1" in "localhost:1"
-    #[arg(default_value = "1")]
-    display_number: u16,
-
-    /// Use /etc/resolv.conf to determine the host IP address rather than parsing the output of
-    /// `ip route`
-    #[arg(short = 'R', long)]
-    resolv_conf: bool,
-}
-
-fn run() -> Result<Option<String>> {
-    let args = Args::parse();
-    DEBUG.store(args.verbose, Ordering::Relaxed);
-
-    // read /etc/resolv.conf, find the first nameserver, and parse it as an ip address
-    let host_ip = if args.resolv_conf { host_ip_from_resolv_conf() } else { host_ip_from_route() }?;
-
-    let port = DISPLAY_PORT_OFFSET
-        .checked_add(args.display_number)
-        .ok_or_else(|| anyhow!("display offset overflowed max port number"))?;
-
-    let sa = SocketAddr::new(host_ip, port);
-    debug!("connecting to {}", sa);
-
-    for retry in 1..=args.retries {
-        debug!("connect attempt {}", retry);
-        match TcpStream::connect_timeout(&sa, args.timeout) {
-            Ok(conn) => {
-                debug!("connection succeeded: {:?}", conn);
-                return Ok(Some(format!("{}:{}", host_ip, args.display_number)));
-                // conn goes out of scope and is dropped, closing the connection
+/// Replace every `@file` argument with the contents of that file, one argument per line. Blank
+/// lines are skipped, and arguments in the file aren't expanded again.
+fn expand_response_files(args: impl Iterator<Item = OsString>) -> Result<Vec<OsString>> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+            Some(path) if !path.is_empty() => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read response file {path}"))?;
+                let lines = contents.lines().map(|line| line.trim_end_matches('\r'));
+                expanded.extend(lines.filter(|line| !line.is_empty()).map(OsString::from));
             }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
 
-            Err(e) => {
-                debug!("connection failed: {}", e);
-                match e.kind() {
-                    // timeout, retry immediately
-                    ErrorKind::TimedOut => (),
-                    // connection refused, wait for timeout before retrying
-                    ErrorKind::ConnectionRefused => sleep(args.timeout),
-                    // bail on any other errors
-                    _ => return Err(e.into()),
-                }
+/// Run the program and print its output, returning whether an X server was found
+fn run() -> Result<bool> {
+    let run_start = Instant::now();
+    let argv = expand_response_files(env::args_os())?;
+    let matches = Args::command().get_matches_from(&argv);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.compat {
+        args.pin_compat();
+    }
+    args.apply_mode();
+    if args.fast || args.icon {
+        args.apply_fast();
+        HIDE_WARNINGS.store(true, Ordering::Relaxed);
+    }
+    set_verbosity(args.verbose);
+    set_strict(args.strict);
+    JSON_ERRORS.store(args.json_errors, Ordering::Relaxed);
+    set_color(use_color(args.color));
+    // printed when this returns, however that happens
+    let _timing = args.timing.then(|| Timing(run_start));
+
+    if let Some(Mode::Completions(CompletionsArgs { shell })) = args.mode {
+        // generate() panics on write errors, so don't give it stdout directly
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Args::command(), env!("CARGO_PKG_NAME"), &mut script);
+        io::stdout().lock().write_all(&script)?;
+        return Ok(true);
+    }
+
+    if args.version_json {
+        outln!("{}", version_json())?;
+        return Ok(true);
+    }
+
+    if args.emit_function {
+        outln!(
+            "{}",
+            format_function(args.shell, &function_command(&argv, matches.get_flag("export"))?)?
+        )?;
+        return Ok(true);
+    }
+
+    if args.print_method {
+        for method in args.methods() {
+            outln!("{}", method.name())?;
+        }
+        if args.verbose > 0 {
+            eprintln!("methods set by {}", method_source(&args, &matches));
+        }
+        return Ok(true);
+    }
+
+    if args.list_methods {
+        let methods = Method::value_variants().iter().map(|&m| (m, method_available(&args, m)));
+        if args.json || args.json_pretty {
+            let js: Value = methods
+                .map(|(method, available)| {
+                    json!({
+                        "name": method.name(),
+                        "description": method.description(),
+                        "available": available,
+                    })
+                })
+                .collect();
+            outln!("{}", if args.json_pretty { format!("{:#}", js) } else { js.to_string() })?;
+        } else {
+            for (method, available) in methods {
+                let available = if available { "available" } else { "unavailable" };
+                outln!("{:<12}{:<13}{}", method.name(), available, method.description())?;
+            }
+        }
+        return Ok(true);
+    }
+
+    if args.plan {
+        let plan = execution_plan(&args);
+        outln!("{}", if args.json_pretty { format!("{:#}", plan) } else { plan.to_string() })?;
+        return Ok(true);
+    }
+
+    if args.list_candidates {
+        for candidate in host_candidates(&args)? {
+            let sources: Vec<&str> = candidate.sources.iter().map(|m| m.name()).collect();
+            // no sources means it was given with --host or --host-file
+            let sources = if sources.is_empty() { "host".to_owned() } else { sources.join(",") };
+            outln!("{} {}", format_ip(candidate.ip, args.ip_format), sources)?;
+        }
+        return Ok(true);
+    }
+
+    if let Some(format) = args.report {
+        let (report, outcome) = detection_report(&args);
+        match format {
+            ReportFormat::Json => outln!("{:#}", report)?,
+            ReportFormat::Toml => write!(io::stdout().lock(), "{}", report_to_toml(&report)?)?,
+        }
+        return Ok(outcome.is_some_and(|outcome| outcome.display.is_some()));
+    }
+
+    if let Some(path) = &args.query {
+        return query(path);
+    }
+
+    if args.host_from_stdin {
+        read_host_from_stdin(&mut args)?;
+    }
+
+    if args.probe_only {
+        args.retries = 1;
+        args.retry_schedule = None;
+    }
+
+    if args.from_xauth {
+        let displays = xauth_displays()?;
+        if displays.is_empty() {
+            if args.verbose > 0 {
+                eprintln!("no Xauthority entries, using display {}", args.display_number);
             }
+        } else {
+            args.scan = Some(DisplayList(displays));
         }
     }
 
-    debug!("retries exhausted, no server found");
-    Ok(None)
+    // with --refresh-cache, only written to
+    let cache = args.cache.clone().filter(|_| !args.refresh_cache);
+    let ranked = match cache.as_deref() {
+        Some(path) if args.adaptive_order => cached_method_order(path),
+        Some(path) => cached_method(path).into_iter().collect(),
+        None => Vec::new(),
+    };
+    if !ranked.is_empty() {
+        if args.verbose > 0 {
+            let names: Vec<&str> = ranked.iter().map(|m| m.name()).collect();
+            eprintln!("trying the cached {} method(s) first", names.join(", "));
+        }
+        args.try_methods_first(&ranked);
+    }
+    if let (Some(ip), None, None) =
+        (cache.as_deref().and_then(cached_host), args.host, &args.host_file)
+    {
+        if args.verbose > 0 {
+            eprintln!("using the cached host IP {ip}");
+        }
+        args.host = Some(ip);
+    }
+
+    if args.bitmask {
+        let mask = display_bitmask(&args)?;
+        // only set once, and nothing else has
+        let _ = BITMASK_STATUS.set(mask);
+        return Ok(true);
+    }
+
+    if let Some(interval) = args.watch {
+        return watch(&args, interval);
+    }
+    if let Some(path) = &args.serve {
+        return serve(&args, path);
+    }
+
+    // checked before doing anything, so a bad descriptor fails fast
+    let results_fd = args.results_fd.map(open_results_fd).transpose()?;
+
+    let start = Instant::now();
+    let result = match &args.lock {
+        Some(path) => find_display_locked(&args, path),
+        None => find_display(&args),
+    };
+    // the last resort, only when there's someone at a terminal to ask
+    let result = match result {
+        Ok(outcome) if outcome.display.is_some() => Ok(outcome),
+        result if args.interactive && io::stdin().is_terminal() && io::stderr().is_terminal() => {
+            prompt_for_host(&args)?.map_or(result, Ok)
+        }
+        result => result,
+    };
+    if let Some(path) = &args.log_file {
+        // don't let logging problems get in the way of the actual result
+        if let Err(e) = append_history(path, &result, start.elapsed()) {
+            emit_warning(format!("{e:#}"));
+        }
+    }
+    let outcome = match result {
+        // a prompt shows the failure, not the reason for it
+        Err(e) if args.icon => {
+            if args.verbose > 0 {
+                eprintln!("{} {:#}", error_label(), e);
+            }
+            outln!("{}", args.icon_missing)?;
+            return Ok(false);
+        }
+        result => result?,
+    };
+    if let (Some(path), Some(method), Some(_)) = (&args.cache, outcome.method, &outcome.display) {
+        let save = if args.refresh_cache { reset_cached_method } else { save_cached_method };
+        if let Err(e) = save(path, method) {
+            emit_warning(format!("{e:#}"));
+        }
+    }
+
+    if let Some(count) = args.probe_count {
+        return print_latencies(&args, &outcome, count);
+    }
+
+    if let Some(output) = format_output(&args, &outcome) {
+        // write the file first, so that anything reading it later sees what was just printed
+        if let Some(path) = &args.write {
+            write_atomic(path, &output)?;
+        }
+        outln!("{}", output)?;
+    }
+
+    if let (Some(true), Some(host), false) = (outcome.host_reachable, outcome.host, args.probe_only)
+    {
+        eprintln!(
+            "{host} is reachable on port {}, but no X server answered. Is the X port blocked by a \
+             firewall?",
+            args.sentinel_port.unwrap_or_default()
+        );
+    }
+    if let (Some(display), true) = (&outcome.display, args.port_fuzz > 0) {
+        let (_, number, _) = parse_display(display)?;
+        if !args.requested_displays().contains(&number) {
+            let port = |display: u16| u32::from(DISPLAY_PORT_OFFSET) + u32::from(display);
+            eprintln!(
+                "note: nothing answered on port {}, but port {} did, so the display is {}",
+                port(args.display_number),
+                port(number),
+                display
+            );
+        }
+    }
+    #[cfg(feature = "tls")]
+    if let (ProbeProtocol::Tls, Some(display)) = (args.probe_protocol, &outcome.display) {
+        eprintln!(
+            "note: the X server at {display} only answers over TLS, so X clients will need a TLS \
+             tunnel like stunnel to reach it"
+        );
+    }
+    if args.verbose > 0 {
+        for attempt in &outcome.method_log {
+            eprintln!("method {attempt}");
+        }
+    }
+    if let (Some(summary), true) = (outcome.error_summary(), args.verbose > 0) {
+        eprintln!("failed attempts: {summary}");
+    }
+    if let (Some(hint), true) = (outcome.hint(), args.verbose > 0) {
+        eprintln!("hint: {}", hint);
+    }
+    if let (Some(template), Some(_)) = (&args.message, &outcome.display) {
+        eprintln!("{}", format_message(template, &outcome));
+    }
+    if let (Some(mut file), Some(_)) = (results_fd, &outcome.display) {
+        writeln!(file, "{}", variable_lines(&outcome))
+            .context("failed to write to --results-fd")?;
+    }
+    if let (true, Some(display)) = (args.apply_systemd, &outcome.display) {
+        apply_systemd_env(display)?;
+    }
+    if let (Some(path), Some(_)) = (&args.apply_environment_d, &outcome.display) {
+        let path = environment_d_path(path)?;
+        let changed = apply_environment_d(&path, &outcome)?;
+        if args.verbose > 0 {
+            let status = if changed { "wrote" } else { "no change to" };
+            eprintln!("{} {}", status, path.display());
+        }
+    }
+    if let (Some(name), Some(display)) = (&args.apply_windows, &outcome.display) {
+        apply_windows_env(name, display, args.windows_timeout)?;
+    }
+    Ok(outcome.display.is_some())
+}
+
+/// Whether fatal errors are printed as JSON, set by --json-errors once the arguments are parsed
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// The exit status for --bitmask, set once the displays have been probed
+static BITMASK_STATUS: OnceLock<u8> = OnceLock::new();
+
+/// Whether warnings are left out, set by --fast and --icon
+static HIDE_WARNINGS: AtomicBool = AtomicBool::new(false);
+
+/// What kind of failure `e` is, for --json-errors
+fn failure_kind(e: &anyhow::Error) -> &'static str {
+    if e.is::<DetectionError>() {
+        "detection"
+    } else if e.is::<HostRefused>() || e.is::<ConnectError>() {
+        "connect"
+    } else if e.is::<io::Error>() {
+        "io"
+    } else {
+        "other"
+    }
 }
 
 fn main() {
-    match run() {
-        Ok(Some(s)) => println!("{}", s),
-        Ok(None) => exit(1),
+    let result = run();
+    if let (Err(e), true) = (&result, JSON_ERRORS.load(Ordering::Relaxed)) {
+        let mut js = json!({ "error": format!("{e:#}"), "kind": failure_kind(e), "found": false });
+        let warnings: Vec<String> = take_warnings().iter().map(ToString::to_string).collect();
+        if !warnings.is_empty() {
+            js["warnings"] = warnings.into();
+        }
+        eprintln!("{}", js);
+    }
+    // warnings are held until the end, so they don't get mixed in with the output
+    let warnings = take_warnings();
+    if !HIDE_WARNINGS.load(Ordering::Relaxed) {
+        for warning in warnings {
+            eprintln!("{} {}", warning_label(), warning);
+        }
+    }
+    if let (Ok(_), Some(&mask)) = (&result, BITMASK_STATUS.get()) {
+        exit(mask.into());
+    }
+    match result {
+        Ok(true) => (),
+        Ok(false) => exit(1),
+        Err(e) if e.is::<HostRefused>() => exit(3),
+        // whatever was reading stdout went away, exit like a process killed by SIGPIPE would
+        Err(e)
+            if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::BrokenPipe) =>
+        {
+            exit(141)
+        }
+        Err(_) if JSON_ERRORS.load(Ordering::Relaxed) => exit(2),
         Err(e) => {
-            eprintln!("Error: {:#}", e);
+            eprintln!("{} {:#}", error_label(), e);
             exit(2);
         }
     };
@@ -0,0 +1,112 @@
+//! Looking up the kernel's neighbor (ARP/NDP) entry for the host, for --report
+
+use std::fs;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::detect::run_ip;
+
+/// A neighbor table entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    /// The link-layer (MAC) address, None if it couldn't be resolved
+    pub lladdr: Option<String>,
+    /// The interface name
+    pub dev: Option<String>,
+    /// The entry state, e.g. "REACHABLE", "STALE", or "FAILED". Entries read from /proc/net/arp
+    /// only know whether they're "COMPLETE", "INCOMPLETE", or "PERMANENT".
+    pub state: Vec<String>,
+}
+
+impl Neighbor {
+    /// Convert to JSON for the report
+    pub fn to_json(&self) -> Value {
+        json!({ "lladdr": self.lladdr, "dev": self.dev, "state": self.state })
+    }
+}
+
+/// Find the neighbor entry for `ip` using `ip -json neigh show`, falling back to /proc/net/arp
+/// for IPv4 if `ip` fails. Ok(None) means there's no entry at all, which while the host doesn't
+/// answer means it's truly unreachable rather than just firewalled.
+pub fn neighbor(ip: IpAddr) -> Result<Option<Neighbor>> {
+    let mut cmd = Command::new("ip");
+    cmd.args(["-json", "neigh", "show", "to", &ip.to_string()]);
+    let err = match run_ip(&mut cmd).and_then(|output| parse_neigh_json(&output, ip)) {
+        Ok(neighbor) => return Ok(neighbor),
+        Err(e) => e,
+    };
+    if ip.is_ipv6() {
+        return Err(err);
+    }
+    debug!("{:#}, reading /proc/net/arp instead", err);
+    let contents = fs::read_to_string("/proc/net/arp").context("failed to read /proc/net/arp")?;
+    parse_proc_arp(&contents, ip)
+}
+
+/// Find the entry for `ip` in the output of `ip -json neigh show`
+pub fn parse_neigh_json(output: &[u8], ip: IpAddr) -> Result<Option<Neighbor>> {
+    let js: Value =
+        serde_json::from_reader(Cursor::new(output)).context("failed to parse output as JSON")?;
+    let Value::Array(entries) = js else {
+        bail!("expected JSON array, got {js}");
+    };
+    let string = |entry: &Value, key| entry.get(key).and_then(Value::as_str).map(str::to_owned);
+    for entry in &entries {
+        let Some(dst) = entry.get("dst").and_then(Value::as_str) else {
+            continue;
+        };
+        if dst.parse::<IpAddr>().ok() != Some(ip) {
+            continue;
+        }
+        let state = match entry.get("state") {
+            Some(Value::Array(states)) => {
+                states.iter().filter_map(Value::as_str).map(str::to_owned).collect()
+            }
+            Some(other) => bail!("neighbor state is not an array: {other}"),
+            None => Vec::new(),
+        };
+        return Ok(Some(Neighbor {
+            lladdr: string(entry, "lladdr"),
+            dev: string(entry, "dev"),
+            state,
+        }));
+    }
+    Ok(None)
+}
+
+/// Find the entry for `ip` in the contents of /proc/net/arp, which has a header line and then
+/// columns "IP address", "HW type", "Flags", "HW address", "Mask", and "Device"
+pub fn parse_proc_arp(contents: &str, ip: IpAddr) -> Result<Option<Neighbor>> {
+    // from include/uapi/linux/if_arp.h
+    const ATF_COM: u32 = 0x02;
+    const ATF_PERM: u32 = 0x04;
+
+    for line in contents.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [addr, _hw_type, flags, hw_addr, _mask, dev] = fields[..] else {
+            bail!("wrong number of fields in /proc/net/arp line {line:?}");
+        };
+        if addr.parse::<IpAddr>().ok() != Some(ip) {
+            continue;
+        }
+        let flags = flags
+            .strip_prefix("0x")
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| anyhow!("invalid flags {flags:?} in /proc/net/arp"))?;
+        let complete = flags & ATF_COM != 0;
+        let mut state = vec![if complete { "COMPLETE" } else { "INCOMPLETE" }.to_owned()];
+        if flags & ATF_PERM != 0 {
+            state.push("PERMANENT".to_owned());
+        }
+        return Ok(Some(Neighbor {
+            lladdr: complete.then(|| hw_addr.to_owned()),
+            dev: Some(dev.to_owned()),
+            state,
+        }));
+    }
+    Ok(None)
+}
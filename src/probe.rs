@@ -0,0 +1,1076 @@
+//! Probing for an X server, with retries
+
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Debug};
+use std::fs;
+use std::future::{self, Future};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixStream};
+use std::path::Path;
+use std::pin::pin;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use serde_json::{json, Value};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+
+use crate::detect::{check_allowed_subnet, detect_host_ips_logged, read_host_file};
+use crate::handshake::{verify_x_server, x_handshake};
+use crate::log::ATTEMPT_LOGGED;
+use crate::race::find_display_parallel;
+#[cfg(feature = "tls")]
+use crate::ProbeProtocol;
+#[cfg(feature = "simulate")]
+use crate::Simulate;
+use crate::{
+    format_display, format_display_brackets, format_pulse_server, format_socket_display, Args,
+    AttemptLogLevel, Method, MethodAttempt, ServerInfo, XSocket,
+};
+
+/// X11 port number is 6000 plus the display number
+pub const DISPLAY_PORT_OFFSET: u16 = 6000;
+
+/// Where local X servers put their Unix sockets, named X<n> for display n
+const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
+
+/// The PulseAudio native protocol's TCP port, for --pulse
+pub const PULSE_PORT: u16 = 4713;
+
+/// Error returned with --refuse-is-success when the host refused a connection: it's up, but no X
+/// server is listening yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostRefused;
+
+impl fmt::Display for HostRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("host is reachable but refused the connection, no X server is listening yet")
+    }
+}
+
+impl std::error::Error for HostRefused {}
+
+/// A connection attempt failed in a way that isn't worth retrying. It reads the same as the error
+/// it wraps, this only marks what kind of failure it is, for --json-errors.
+#[derive(Debug)]
+pub struct ConnectError(pub io::Error);
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// The result of looking for an X server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// The DISPLAY string, if an X server was found
+    pub display: Option<String>,
+    /// The host IP address that was probed, None for a local X server
+    pub host: Option<IpAddr>,
+    /// The method that found the host IP, None if no detection was done
+    pub method: Option<Method>,
+    /// The detection methods that were run, in order, ending with the one that found the host
+    /// IP. Empty if no detection was done, and with --parallel, which runs them all.
+    pub method_log: Vec<MethodAttempt>,
+    /// How many connection attempts were made, zero if the result was reused from a lock file
+    pub attempts: u16,
+    /// Every connection attempt made, for --json-verbose
+    pub attempt_log: Vec<Attempt>,
+    /// Whether the host accepted a connection on the --sentinel-port, if one was probed after no X
+    /// server was found
+    pub host_reachable: Option<bool>,
+    /// What the X server said about itself, with --handshake
+    pub server: Option<ServerInfo>,
+    /// The PULSE_SERVER string, with --pulse if a PulseAudio server answered on the host
+    pub pulse_server: Option<String>,
+    /// With --dual-stack-policy both, the DISPLAY for the IPv6 host when both an IPv4 and an IPv6
+    /// host have an X server. `display` is then the IPv4 one.
+    pub other_display: Option<String>,
+}
+
+impl Outcome {
+    /// Get the outcome as a JSON object for --json output
+    pub fn to_json(&self) -> Value {
+        let mut js = json!({
+            "found": self.display.is_some(),
+            "display": self.display,
+            "host": self.host.map(|ip| ip.to_string()),
+            "method": self.method.map(Method::name),
+            "attempts": self.attempts,
+            "host_reachable": self.host_reachable,
+        });
+        if let Some(server) = &self.server {
+            js["server"] = server.to_json();
+        }
+        if let Some(pulse_server) = &self.pulse_server {
+            js["pulse_server"] = pulse_server.as_str().into();
+        }
+        if let Some(other_display) = &self.other_display {
+            js["other_display"] = other_display.as_str().into();
+        }
+        if !self.method_log.is_empty() {
+            js["methods"] = self.method_log.iter().map(MethodAttempt::to_json).collect();
+        }
+        let errors = self.error_counts();
+        if !errors.is_empty() {
+            let errors: serde_json::Map<String, Value> =
+                errors.iter().map(|(kind, count)| (format!("{kind:?}"), (*count).into())).collect();
+            js["errors"] = errors.into();
+        }
+        js
+    }
+
+    /// Count the failed attempts by why they failed, in the order each kind of failure first
+    /// happened
+    pub fn error_counts(&self) -> Vec<(ErrorKind, u16)> {
+        let mut counts: Vec<(ErrorKind, u16)> = Vec::new();
+        for kind in self.attempt_log.iter().filter_map(|a| a.error) {
+            match counts.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, count)) => *count = count.saturating_add(1),
+                None => counts.push((kind, 1)),
+            }
+        }
+        counts
+    }
+
+    /// Summarize error_counts() for people, like "8 timed out, 1 connection refused"
+    pub fn error_summary(&self) -> Option<String> {
+        let counts = self.error_counts();
+        let counts: Vec<String> =
+            counts.iter().map(|(kind, count)| format!("{count} {kind}")).collect();
+        (!counts.is_empty()).then(|| counts.join(", "))
+    }
+
+    /// Get the outcome as a JSON object for --json-verbose output, which is like to_json() but
+    /// with an array of every connection attempt in place of the attempt count
+    pub fn to_json_verbose(&self) -> Value {
+        let mut js = self.to_json();
+        js["attempts"] = self.attempt_log.iter().map(Attempt::to_json).collect();
+        js
+    }
+
+    /// Get a hint about the Windows X server when the host was found but refused every
+    /// connection, which almost always means the X server isn't running or isn't accepting TCP
+    /// connections
+    pub fn hint(&self) -> Option<&'static str> {
+        let all_refused = !self.attempt_log.is_empty()
+            && self.attempt_log.iter().all(|a| a.error == Some(ErrorKind::ConnectionRefused));
+        (self.display.is_none() && self.host.is_some() && all_refused).then_some(
+            "the host refused every connection. Is the X server running on Windows, and is it \
+             set to listen on TCP with access control disabled (e.g. VcXsrv's \"Disable access \
+             control\")?",
+        )
+    }
+}
+
+/// A single connection attempt made while probing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    /// The host IP address connected to, None for a Unix socket
+    pub host: Option<IpAddr>,
+    /// The display number probed
+    pub display: u16,
+    /// The TCP port connected to, which is the --port if one was given, None for a Unix socket
+    pub port: Option<u16>,
+    /// Why the attempt failed, None if it connected
+    pub error: Option<ErrorKind>,
+    /// How long the attempt took
+    pub latency: Duration,
+}
+
+impl Attempt {
+    /// Get the attempt as a JSON object for --json-verbose output
+    pub fn to_json(&self) -> Value {
+        json!({
+            "host": self.host.map(|ip| ip.to_string()),
+            "port": self.port,
+            "display": self.display,
+            "error": self.error.map(|kind| format!("{kind:?}")),
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        })
+    }
+}
+
+/// Find the host IP and probe it for an X server
+pub fn find_display(args: &Args) -> Result<Outcome> {
+    warn_out_of_range(args)?;
+    find_display_inner(args)
+}
+
+/// Warn about the displays that won't be probed because their TCP port would be past 65535. They
+/// can come from --scan, --display-offset or --port-fuzz, so they're skipped rather than fatal.
+pub(crate) fn warn_out_of_range(args: &Args) -> Result<()> {
+    let skipped = args.out_of_range_displays();
+    if !skipped.is_empty() {
+        warning!("skipping display(s) {skipped:?}, their X port would be past 65535");
+    }
+    Ok(())
+}
+
+/// find_display(), without warning about out of range displays again for every --host-file host
+fn find_display_inner(args: &Args) -> Result<Outcome> {
+    if args.parallel {
+        return find_display_parallel(args);
+    }
+
+    if let (Some(path), None) = (&args.host_file, args.host) {
+        return find_display_host_file(args, path);
+    }
+
+    let deadline = args.budget.map(|budget| Instant::now() + budget);
+
+    if args.local {
+        let (found, attempt_log) = scan_displays(args, deadline, None, |display, _timeout| {
+            connect_local_x(display, args.x_socket)
+        })?;
+        return Ok(Outcome {
+            display: found.map(|display| format_display(None, display, args.screen)),
+            attempts: attempt_count(&attempt_log),
+            attempt_log,
+            ..Outcome::default()
+        });
+    }
+
+    if let Some(path) = &args.unix_socket {
+        debug!("probing Unix socket {}", path.display());
+        let (found, attempt_log) =
+            retry_probe(args, deadline, None, args.display_number, |timeout| {
+                connect_unix(path, timeout)
+            })?;
+        return Ok(Outcome {
+            display: found.then(|| format_socket_display(path, args.display_number, args.screen)),
+            attempts: attempt_count(&attempt_log),
+            attempt_log,
+            ..Outcome::default()
+        });
+    }
+
+    // detect the host once up front, no matter how many displays are scanned
+    let mut method_log = Vec::new();
+    let (host_ips, method) = match args.host {
+        Some(ip) => (vec![ip], None),
+        None => detect_host_ips_logged(args, &mut method_log).map(|(ips, m)| (ips, Some(m)))?,
+    };
+    let host_ip = host_ips[0];
+
+    check_allowed_subnet(args, host_ip)?;
+
+    // like --parallel, every nameserver gets a turn, not just the first
+    let ips = allowed_candidates(args, host_ips);
+    if ips.len() > 1 && !args.no_validate {
+        debug!("trying each of the nameservers {:?}", ips);
+        let outcome = find_display_hosts(args, &ips)?;
+        return Ok(Outcome { method, method_log, ..outcome });
+    }
+
+    if args.no_validate {
+        debug!("not probing {} because of --no-validate", host_ip);
+        return Ok(Outcome {
+            display: Some(format_display_brackets(
+                Some(host_ip),
+                args.display_number,
+                args.screen,
+                !args.ipv6_no_brackets,
+            )),
+            host: Some(host_ip),
+            method,
+            method_log,
+            ..Outcome::default()
+        });
+    }
+
+    if args.warmup {
+        warm_up(args, host_ip, attempt_timeout(args, time_left(deadline)));
+    }
+
+    // the lookup can be slow, so run it in the background while we probe
+    let name_lookup = args.resolve_name.then(|| spawn_reverse_lookup(host_ip));
+
+    let (found, attempt_log) = scan_displays(args, deadline, Some(host_ip), |display, timeout| {
+        let sa = host_socket_addr(args, host_ip, display).map_err(io::Error::other)?;
+        attempt_debug!("connecting to {}", sa);
+        connect_x(args, &sa, timeout)
+    })?;
+
+    if let Some(name_lookup) = name_lookup {
+        let name = name_lookup
+            .recv_timeout(args.timeout)
+            .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::TimedOut, "timed out")));
+        report_reverse_lookup(host_ip, name);
+    }
+
+    // tell a blocked X port apart from an unreachable host
+    let host_reachable = match args.sentinel_port {
+        Some(port) if found.is_none() => {
+            let sa = SocketAddr::new(host_ip, port);
+            debug!("probing sentinel port {}", sa);
+            Some(report_sentinel(sa, connect_tcp(&sa, args.timeout, false)))
+        }
+        _ => None,
+    };
+
+    let server = match found {
+        Some(display) if args.handshake => {
+            let sa = host_socket_addr(args, host_ip, display)?;
+            Some(x_handshake(args, &sa)?)
+        }
+        _ => None,
+    };
+    let pulse_server = probe_pulse(args, host_ip);
+
+    Ok(Outcome {
+        display: found.map(|display| {
+            format_display_brackets(Some(host_ip), display, args.screen, !args.ipv6_no_brackets)
+        }),
+        host: Some(host_ip),
+        method,
+        method_log,
+        attempts: attempt_count(&attempt_log),
+        attempt_log,
+        host_reachable,
+        server,
+        pulse_server,
+        ..Outcome::default()
+    })
+}
+
+/// Probe each host in the --host-file at `path` in turn, returning the first to have an X server
+fn find_display_host_file(args: &Args, path: &Path) -> Result<Outcome> {
+    find_display_hosts(args, &read_host_file(path)?)
+}
+
+/// The host IPs from detection that are in an --allow-subnet
+pub(crate) fn allowed_candidates(args: &Args, mut ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    ips.retain(|&ip| check_allowed_subnet(args, ip).is_ok());
+    ips
+}
+
+/// Probe each of `ips` in turn, returning the first to have an X server. The --budget covers all
+/// of them, and the attempts made on each are added up. If none has one, the outcome is the last
+/// host's.
+fn find_display_hosts(args: &Args, ips: &[IpAddr]) -> Result<Outcome> {
+    block_on_ready(find_display_hosts_with(args, ips, |host_args| {
+        future::ready(find_display_inner(&host_args))
+    }))
+}
+
+/// find_display_hosts(), generic over how to probe each host so that find_display_async() shares
+/// it. `find` is given `args` with the host to probe and the time left of the --budget.
+pub(crate) async fn find_display_hosts_with<F>(
+    args: &Args,
+    ips: &[IpAddr],
+    mut find: impl FnMut(Args) -> F,
+) -> Result<Outcome>
+where
+    F: Future<Output = Result<Outcome>>,
+{
+    let deadline = args.budget.map(|budget| Instant::now() + budget);
+    let mut attempt_log = Vec::new();
+    let mut last = Outcome::default();
+    let mut last_error = None;
+    for &ip in ips {
+        let mut host_args = args.clone();
+        host_args.host = Some(ip);
+        host_args.budget = time_left(deadline);
+        if host_args.budget.is_some_and(|left| left.is_zero()) {
+            debug!("time budget exhausted before probing {}", ip);
+            break;
+        }
+        debug!("probing {}", ip);
+        match find(host_args).await {
+            Ok(outcome) if outcome.display.is_some() => {
+                attempt_log.extend(outcome.attempt_log);
+                return Ok(Outcome {
+                    attempts: attempt_count(&attempt_log),
+                    attempt_log,
+                    ..outcome
+                });
+            }
+            Ok(mut outcome) => {
+                attempt_log.append(&mut outcome.attempt_log);
+                last = outcome;
+            }
+            Err(e) => {
+                debug!("probing {} failed: {:#}", ip, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    match last_error {
+        Some(e) if attempt_log.is_empty() => Err(e),
+        _ => Ok(Outcome { attempts: attempt_count(&attempt_log), attempt_log, ..last }),
+    }
+}
+
+/// Connect to X `display` on `host` `count` times in a row for --probe-count, and get how long
+/// each successful connection took to set up, sorted from fastest to slowest. Failed connections
+/// are left out.
+pub fn probe_latencies(
+    args: &Args,
+    host: IpAddr,
+    display: u16,
+    count: u16,
+) -> Result<Vec<Duration>> {
+    let sa = host_socket_addr(args, host, display)?;
+    if args.warmup {
+        warm_up(args, host, args.timeout);
+    }
+    let mut latencies = Vec::new();
+    for i in 1..=count {
+        let start = Instant::now();
+        match connect_tcp(&sa, args.timeout, args.nodelay) {
+            Ok(_) => latencies.push(start.elapsed()),
+            Err(e) => debug!("latency probe {} of {} to {} failed: {}", i, count, sa, e),
+        }
+    }
+    latencies.sort();
+    Ok(latencies)
+}
+
+/// Make a throwaway connection to the X port on `host` for --warmup, ignoring how it goes. On a
+/// cold network the first connection to the host waits for its ARP (or NDP) lookup, which this
+/// gets out of the way so that the connections that count aren't slowed down or timed out by it.
+pub(crate) fn warm_up(args: &Args, host: IpAddr, timeout: Duration) {
+    let Ok(sa) = host_socket_addr(args, host, args.display_number) else {
+        return;
+    };
+    let start = Instant::now();
+    let result = connect_tcp(&sa, timeout, false);
+    match result {
+        Ok(_) => debug!("warm-up connection to {} connected in {:?}", sa, start.elapsed()),
+        Err(e) => debug!("warm-up connection to {} failed after {:?}: {}", sa, start.elapsed(), e),
+    }
+}
+
+/// With --pulse, check for a PulseAudio server on `host` and get its PULSE_SERVER string
+pub(crate) fn probe_pulse(args: &Args, host: IpAddr) -> Option<String> {
+    if !args.pulse {
+        return None;
+    }
+    let sa = SocketAddr::new(host, PULSE_PORT);
+    debug!("probing PulseAudio server {}", sa);
+    match connect_tcp(&sa, args.timeout, false) {
+        Ok(_) => Some(format_pulse_server(host, PULSE_PORT)),
+        Err(e) => {
+            debug!("no PulseAudio server at {}: {}", sa, e);
+            None
+        }
+    }
+}
+
+/// Get the TCP port for an X display number
+pub(crate) fn display_port(display: u16) -> Result<u16> {
+    DISPLAY_PORT_OFFSET
+        .checked_add(display)
+        .ok_or_else(|| anyhow!("display offset overflowed max port number"))
+}
+
+/// Get the socket address to probe for an X display on the host, or the --port if one was given
+pub(crate) fn host_socket_addr(args: &Args, host_ip: IpAddr, display: u16) -> Result<SocketAddr> {
+    // Connecting to a link-local address without a scope id fails with a cryptic "invalid
+    // argument" error, so explain what's going on instead. Nothing here can tell which
+    // interface the zone should be.
+    if let IpAddr::V6(ip) = host_ip {
+        ensure!(
+            !ip.is_unicast_link_local(),
+            "host IP {ip} is an IPv6 link-local address, which can't be connected to without a \
+             zone (scope id) naming the interface, like {ip}%eth0. Use a detection method that \
+             finds an IPv4 or global IPv6 address instead."
+        );
+    }
+    Ok(SocketAddr::new(host_ip, probe_port(args, display)?))
+}
+
+/// Get the TCP port to probe for an X display, the --port if one was given
+pub(crate) fn probe_port(args: &Args, display: u16) -> Result<u16> {
+    match args.port {
+        Some(port) => Ok(port),
+        None => display_port(display),
+    }
+}
+
+/// Start a reverse DNS lookup of `ip` in a background thread, the result is sent to the returned
+/// channel. If the lookup hangs, the thread is simply abandoned when the process exits.
+fn spawn_reverse_lookup(ip: IpAddr) -> Receiver<io::Result<String>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // the receiver may have given up already, that's fine
+        let _ = tx.send(dns_lookup::lookup_addr(&ip).map_err(io::Error::from));
+    });
+    rx
+}
+
+/// Print the result of a --resolve-name lookup
+pub(crate) fn report_reverse_lookup(ip: IpAddr, name: io::Result<String>) {
+    match name {
+        Ok(name) => eprintln!("host {ip} is {name}"),
+        Err(e) => eprintln!("reverse lookup of {ip} failed: {e}"),
+    }
+}
+
+/// Log the result of probing the --sentinel-port at `sa`, returning whether it connected
+pub(crate) fn report_sentinel<T>(sa: SocketAddr, result: io::Result<T>) -> bool {
+    match result {
+        Ok(_) => {
+            debug!("sentinel {} connected, the host is reachable but X isn't", sa);
+            true
+        }
+        Err(e) => {
+            debug!("sentinel {} failed too, the host looks unreachable: {}", sa, e);
+            false
+        }
+    }
+}
+
+/// Connect to a TCP X server for probing
+pub(crate) fn connect_tcp(
+    sa: &SocketAddr,
+    timeout: Duration,
+    nodelay: bool,
+) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(sa, timeout)?;
+    tune_probe_socket(SockRef::from(&stream), nodelay)?;
+    Ok(stream)
+}
+
+/// How many ports from --connect-source-port up to try before giving up
+const SOURCE_PORT_TRIES: u16 = 8;
+
+/// Create a TCP socket for connecting to `sa`, bound to the local `port` or the first free one
+/// after it
+pub(crate) fn bind_source_port(sa: &SocketAddr, port: u16) -> io::Result<Socket> {
+    let local_ip: IpAddr = match sa {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let last = port.saturating_add(SOURCE_PORT_TRIES - 1);
+    for p in port..=last {
+        let sock = Socket::new(Domain::for_address(*sa), Type::STREAM, Some(Protocol::TCP))?;
+        // without this, binding the port again for the next retry can fail while the previous
+        // probe is still closing
+        sock.set_reuse_address(true)?;
+        match sock.bind(&SocketAddr::new(local_ip, p).into()) {
+            Ok(()) => {
+                if p != port {
+                    debug!("source port {} is in use, using {}", port, p);
+                }
+                return Ok(sock);
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::AddrInUse,
+        format!("--connect-source-port: local ports {port}-{last} are all in use"),
+    ))
+}
+
+/// Create the socket for an X probe of `sa` when it needs setting up before connecting, bound to
+/// the --connect-source-port or with --tcp-syn-retries. None if a plain connection will do.
+pub(crate) fn probe_socket(args: &Args, sa: &SocketAddr) -> io::Result<Option<Socket>> {
+    let sock = match (args.connect_source_port, args.tcp_syn_retries) {
+        (None, None) => return Ok(None),
+        (Some(port), _) => bind_source_port(sa, port)?,
+        (None, Some(_)) => {
+            Socket::new(Domain::for_address(*sa), Type::STREAM, Some(Protocol::TCP))?
+        }
+    };
+    if let Some(retries) = args.tcp_syn_retries {
+        set_syn_retries(&sock, retries)?;
+    }
+    Ok(Some(sock))
+}
+
+/// Set TCP_SYNCNT on `sock` for --tcp-syn-retries, which socket2 has no setter for
+#[cfg(target_os = "linux")]
+fn set_syn_retries(sock: &Socket, retries: u8) -> io::Result<()> {
+    let value = libc::c_int::from(retries);
+    // SAFETY: the fd is an open socket for as long as `sock` is borrowed, and the option value is
+    // a c_int of exactly the size passed
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_SYNCNT,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// TCP_SYNCNT is Linux only
+#[cfg(not(target_os = "linux"))]
+fn set_syn_retries(_sock: &Socket, _retries: u8) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "--tcp-syn-retries is only supported on Linux"))
+}
+
+/// Connect to the X server at `sa`, and with --strict-connect make sure it really is one
+pub(crate) fn connect_x(args: &Args, sa: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let mut stream = match probe_socket(args, sa)? {
+        Some(sock) => {
+            sock.connect_timeout(&(*sa).into(), timeout)?;
+            let stream = TcpStream::from(sock);
+            tune_probe_socket(SockRef::from(&stream), args.nodelay)?;
+            stream
+        }
+        None => connect_tcp(sa, timeout, args.nodelay)?,
+    };
+    verify_probe(args, &mut stream)?;
+    Ok(stream)
+}
+
+/// Check a new probe connection further than it connecting, with the X connection setup for
+/// --strict-connect or the TLS handshake for --probe-protocol tls
+pub(crate) fn verify_probe(args: &Args, stream: &mut TcpStream) -> io::Result<()> {
+    if args.strict_connect {
+        verify_x_server(args, stream)?;
+    }
+    #[cfg(feature = "tls")]
+    if uses_tls(args) && !args.strict_connect {
+        crate::tls::handshake(args, stream)?;
+    }
+    if let Some(dwell) = args.require_established_ms {
+        check_established(stream, dwell)?;
+    }
+    if let Some(command) = &args.validate_with {
+        validate_with(args, command, stream.peer_addr()?)?;
+    }
+    Ok(())
+}
+
+/// Run the --validate-with `command` with DISPLAY set to the X server at `sa`. It fails with an
+/// InvalidData error, which is retried, if the command fails or outlasts --validate-timeout.
+fn validate_with(args: &Args, command: &str, sa: SocketAddr) -> io::Result<()> {
+    let display = match args.port {
+        Some(_) => args.display_number,
+        None => sa.port().saturating_sub(DISPLAY_PORT_OFFSET),
+    };
+    let display =
+        format_display_brackets(Some(sa.ip()), display, args.screen, !args.ipv6_no_brackets);
+    attempt_debug!("validating {} with `{}`", display, command);
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .env("DISPLAY", &display)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("--validate-with command failed with {status}"),
+            ));
+        }
+        if start.elapsed() >= args.validate_timeout {
+            // it's no use anymore, and leaving it running would leave a zombie behind
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("--validate-with command still running after {:?}", args.validate_timeout),
+            ));
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
+
+/// Whether probes need more than a plain connection, see verify_probe()
+pub(crate) fn verifies_probes(args: &Args) -> bool {
+    args.strict_connect
+        || uses_tls(args)
+        || args.require_established_ms.is_some()
+        || args.validate_with.is_some()
+}
+
+/// Wait for `dwell` and then check that the peer hasn't closed `stream`, for
+/// --require-established-ms. A closed connection is a ConnectionReset error.
+fn check_established(stream: &mut TcpStream, dwell: Duration) -> io::Result<()> {
+    sleep(dwell);
+    // peek without blocking, end of file means the peer hung up and nothing pending means it's
+    // still there
+    stream.set_nonblocking(true)?;
+    let result = stream.peek(&mut [0u8; 1]);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(0) => Err(io::Error::new(
+            ErrorKind::ConnectionReset,
+            format!("connection was closed within {dwell:?}"),
+        )),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether --probe-protocol tls was given
+#[cfg(feature = "tls")]
+pub(crate) fn uses_tls(args: &Args) -> bool {
+    args.probe_protocol == ProbeProtocol::Tls
+}
+
+/// Without the tls feature, probes are always plain X
+#[cfg(not(feature = "tls"))]
+pub(crate) fn uses_tls(_args: &Args) -> bool {
+    false
+}
+
+/// Set socket options on a probe connection. SO_LINGER is zeroed so that closing the probe resets
+/// the connection rather than leaving a socket in TIME_WAIT, which adds up when polling with lots
+/// of retries.
+pub(crate) fn tune_probe_socket(sock: SockRef, nodelay: bool) -> io::Result<()> {
+    sock.set_linger(Some(Duration::ZERO))?;
+    if nodelay {
+        sock.set_tcp_nodelay(true)?;
+    }
+    Ok(())
+}
+
+/// Connect to the Unix socket at `path`, waiting at most `timeout`
+pub(crate) fn connect_unix(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+    let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    sock.connect_timeout(&SockAddr::unix(path)?, timeout)?;
+    Ok(OwnedFd::from(sock).into())
+}
+
+/// List the display numbers of the X sockets in /tmp/.X11-unix, in order. These are the local X
+/// servers that are probably running, though a server that crashed can leave its socket behind.
+/// A missing directory has no displays.
+pub fn local_displays() -> Vec<u16> {
+    let entries = match fs::read_dir(X11_UNIX_DIR) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("can't list {}: {}", X11_UNIX_DIR, e);
+            return Vec::new();
+        }
+    };
+    let mut displays: Vec<u16> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix('X')?.parse().ok())
+        .collect();
+    displays.sort_unstable();
+    displays
+}
+
+/// Connect to a local X server's Unix socket for the given display number
+pub(crate) fn connect_local_x(display: u16, kind: XSocket) -> io::Result<UnixStream> {
+    let path = format!("{X11_UNIX_DIR}/X{display}");
+    let connect_abstract = || UnixStream::connect_addr(&UnixSocketAddr::from_abstract_name(&path)?);
+    match kind {
+        XSocket::Fs => UnixStream::connect(&path),
+        XSocket::Abstract => connect_abstract(),
+        XSocket::Auto => connect_abstract().or_else(|e| {
+            debug!("abstract socket @{} failed ({}), trying {}", path, e, path);
+            UnixStream::connect(&path)
+        }),
+    }
+}
+
+/// Probe each of the displays selected by `args` in turn, returning the first display number that
+/// `connect` succeeds for and a record of every connection attempt made. `host` is only used for
+/// the record.
+pub(crate) fn scan_displays<T: Debug>(
+    args: &Args,
+    deadline: Option<Instant>,
+    host: Option<IpAddr>,
+    mut connect: impl FnMut(u16, Duration) -> io::Result<T>,
+) -> Result<(Option<u16>, Vec<Attempt>)> {
+    let connect = |display, timeout| future::ready(connect(display, timeout));
+    block_on_ready(scan_displays_with(args, deadline, host, connect, wait_blocking))
+}
+
+/// scan_displays(), generic over how to connect and how to wait between attempts so that
+/// find_display_async() shares it. `connect` and `wait` are awaited one at a time.
+pub(crate) async fn scan_displays_with<T, C, W>(
+    args: &Args,
+    deadline: Option<Instant>,
+    host: Option<IpAddr>,
+    mut connect: impl FnMut(u16, Duration) -> C,
+    mut wait: impl FnMut(Duration) -> W,
+) -> Result<(Option<u16>, Vec<Attempt>)>
+where
+    T: Debug,
+    C: Future<Output = io::Result<T>>,
+    W: Future<Output = ()>,
+{
+    let mut attempt_log = Vec::new();
+    for display in args.displays() {
+        debug!("probing display {}", display);
+        let display_deadline = candidate_deadline(args, deadline);
+        let probe = |timeout| connect(display, timeout);
+        let (connected, log) =
+            retry_probe_with(args, display_deadline, host, display, probe, &mut wait).await?;
+        attempt_log.extend(log);
+        if connected {
+            debug!("found an X server on display {}", display);
+            return Ok((Some(display), attempt_log));
+        }
+        if time_left(deadline).is_some_and(|left| left.is_zero()) {
+            break;
+        }
+    }
+    Ok((None, attempt_log))
+}
+
+/// Call `probe` until it succeeds, we run out of retries, or the `deadline` passes. `probe` is
+/// given the connection timeout to use, which is shortened to fit the deadline. Returns whether it
+/// connected and a record of the attempts made, which `host` and `display` are used for.
+pub(crate) fn retry_probe<T: Debug>(
+    args: &Args,
+    deadline: Option<Instant>,
+    host: Option<IpAddr>,
+    display: u16,
+    mut probe: impl FnMut(Duration) -> io::Result<T>,
+) -> Result<(bool, Vec<Attempt>)> {
+    let probe = |timeout| future::ready(probe(timeout));
+    block_on_ready(retry_probe_with(args, deadline, host, display, probe, wait_blocking))
+}
+
+/// retry_probe(), generic over how to connect and how to wait between attempts like
+/// scan_displays_with()
+pub(crate) async fn retry_probe_with<T, P, W>(
+    args: &Args,
+    deadline: Option<Instant>,
+    host: Option<IpAddr>,
+    display: u16,
+    mut probe: impl FnMut(Duration) -> P,
+    mut wait: impl FnMut(Duration) -> W,
+) -> Result<(bool, Vec<Attempt>)>
+where
+    T: Debug,
+    P: Future<Output = io::Result<T>>,
+    W: Future<Output = ()>,
+{
+    let port = host.and_then(|_| probe_port(args, display).ok());
+    let mut attempt_log = Vec::new();
+    for retry in 1..=args.attempts() {
+        let timeout = match time_left(deadline) {
+            Some(left) if left.is_zero() => {
+                debug!("time budget exhausted after {} attempt(s)", attempt_log.len());
+                return Ok((false, attempt_log));
+            }
+            left => attempt_timeout(args, left),
+        };
+
+        ATTEMPT_LOGGED.set(logs_attempt(args, retry));
+        attempt_debug!("connect attempt {}", retry);
+        let start = Instant::now();
+        let result = match simulated_attempt(args) {
+            Some(result) => {
+                // a simulated timeout takes as long as a real one
+                if result.as_ref().is_err_and(|e| e.kind() == ErrorKind::TimedOut) {
+                    wait(timeout).await;
+                }
+                result
+            }
+            // the connection is closed right away, we only care whether it worked
+            None => probe(timeout).await.map(|conn| attempt_debug!("connected: {:?}", conn)),
+        };
+        let error = result.as_ref().err().map(io::Error::kind);
+        attempt_log.push(Attempt { host, display, port, error, latency: start.elapsed() });
+        check_timeout_breaker(args, &attempt_log)?;
+        match result {
+            Ok(()) => {
+                debug!("connection succeeded after {} attempt(s)", retry);
+                return Ok((true, attempt_log));
+            }
+            Err(e) => {
+                log_changed_error(args, retry, &attempt_log, &e);
+                let delay = retry_delay(args, retry, e)?;
+                wait(time_left(deadline).map_or(delay, |left| delay.min(left))).await;
+            }
+        }
+    }
+
+    debug!("retries exhausted after {} attempt(s), no server found", attempt_log.len());
+    Ok((false, attempt_log))
+}
+
+/// Sleep for `delay`, as the `wait` of the blocking probes
+fn wait_blocking(delay: Duration) -> future::Ready<()> {
+    sleep(delay);
+    future::ready(())
+}
+
+/// Run a future that never has to wait for anything, like scan_displays_with() with blocking
+/// callbacks, to completion on the current thread
+fn block_on_ready<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking probe future wasn't ready"),
+    }
+}
+
+/// Whether attempt number `retry` is logged in full with --attempts-log-level
+pub(crate) fn logs_attempt(args: &Args, retry: u16) -> bool {
+    match args.attempts_log_level {
+        AttemptLogLevel::All => true,
+        AttemptLogLevel::Changes => retry == 1,
+        AttemptLogLevel::Every(n) => {
+            retry == 1 || retry.is_multiple_of(n) || retry == args.attempts()
+        }
+    }
+}
+
+/// With --attempts-log-level changes, log a failed attempt whose error differs from the one
+/// before it. `attempt_log` already has this attempt in it.
+pub(crate) fn log_changed_error(args: &Args, retry: u16, attempt_log: &[Attempt], e: &io::Error) {
+    if let (AttemptLogLevel::Changes, [.., previous, this]) = (args.attempts_log_level, attempt_log)
+    {
+        if previous.error != this.error {
+            debug!("connection failed differently on attempt {}: {}", retry, e);
+        }
+    }
+}
+
+/// The canned result of a connection attempt for --simulate, None to really connect
+#[cfg(feature = "simulate")]
+pub(crate) fn simulated_attempt(args: &Args) -> Option<io::Result<()>> {
+    let kind = match args.simulate? {
+        Simulate::Ok => return Some(Ok(())),
+        Simulate::Refused => ErrorKind::ConnectionRefused,
+        Simulate::Timeout => ErrorKind::TimedOut,
+        Simulate::Unreachable => ErrorKind::HostUnreachable,
+    };
+    Some(Err(io::Error::new(kind, "simulated by --simulate")))
+}
+
+/// Without the simulate feature, always really connect
+#[cfg(not(feature = "simulate"))]
+pub(crate) fn simulated_attempt(_args: &Args) -> Option<io::Result<()>> {
+    None
+}
+
+/// The number of attempts in `attempt_log`, for Outcome::attempts
+pub(crate) fn attempt_count(attempt_log: &[Attempt]) -> u16 {
+    u16::try_from(attempt_log.len()).unwrap_or(u16::MAX)
+}
+
+/// The deadline for probing a single --scan display, the sooner of the overall `deadline` and the
+/// --timeout-per-candidate limit
+pub(crate) fn candidate_deadline(args: &Args, deadline: Option<Instant>) -> Option<Instant> {
+    let candidate = args.timeout_per_candidate.map(|limit| Instant::now() + limit);
+    match (deadline, candidate) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// How long the next connection attempt can take, given the `left` of the time limit if there is
+/// one
+pub(crate) fn attempt_timeout(args: &Args, left: Option<Duration>) -> Duration {
+    match (left, args.timeout_fraction) {
+        // no shorter than a millisecond, so it can't spin on tiny timeouts
+        (Some(left), Some(fraction)) => {
+            left.mul_f64(fraction).max(Duration::from_millis(1)).min(left)
+        }
+        (Some(left), None) => args.timeout.min(left),
+        (None, _) => args.timeout,
+    }
+}
+
+/// How much time is left until `deadline`, if there is one
+pub(crate) fn time_left(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Decide how long to wait after failed connection attempt number `retry`.
+///
+/// Timeouts are retried immediately, refused connections (or missing sockets) after waiting for
+/// --refused-wait (the timeout by default), and any other error is fatal.
+pub(crate) fn retry_delay(args: &Args, retry: u16, e: io::Error) -> Result<Duration> {
+    attempt_debug!("connection failed: {}", e);
+    // no jitter needed after the final attempt
+    let jitter = if retry < args.attempts() { random_jitter(args.jitter) } else { Duration::ZERO };
+    let scheduled = args.retry_schedule.as_ref().and_then(|s| s.0.get(usize::from(retry) - 1));
+    match e.kind() {
+        // the host is up, which is all the caller wanted to know
+        ErrorKind::ConnectionRefused if args.refuse_is_success => {
+            debug!("host refused the connection, not retrying because of --refuse-is-success");
+            Err(HostRefused.into())
+        }
+        // --retry-schedule says exactly how long to wait
+        ErrorKind::TimedOut
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::NotFound
+        | ErrorKind::InvalidData
+        | ErrorKind::ConnectionReset
+            if args.retry_schedule.is_some() =>
+        {
+            Ok(scheduled.copied().unwrap_or_default())
+        }
+        // timeout, retry immediately (plus jitter)
+        ErrorKind::TimedOut => Ok(jitter),
+        // connection refused, wait for --refused-wait (plus jitter) before retrying
+        ErrorKind::ConnectionRefused | ErrorKind::NotFound => {
+            Ok(args.refused_wait.unwrap_or(args.timeout) + jitter)
+        }
+        // with --strict-connect, something other than an X server answered, it may be starting.
+        // Likewise for a server that doesn't speak TLS with --probe-protocol tls, or one that the
+        // --validate-with command didn't accept.
+        ErrorKind::InvalidData if verifies_probes(args) => {
+            Ok(args.refused_wait.unwrap_or(args.timeout) + jitter)
+        }
+        // with --require-established-ms, whatever answered hung up right away
+        ErrorKind::ConnectionReset if args.require_established_ms.is_some() => {
+            Ok(args.refused_wait.unwrap_or(args.timeout) + jitter)
+        }
+        // bail on any other errors
+        _ => Err(ConnectError(e).into()),
+    }
+}
+
+/// Fail if every attempt in `attempt_log` timed out and there are at least as many as
+/// --probe-timeout-is-fatal-after
+pub(crate) fn check_timeout_breaker(args: &Args, attempt_log: &[Attempt]) -> Result<()> {
+    let Some(limit) = args.probe_timeout_is_fatal_after else {
+        return Ok(());
+    };
+    let all_timed_out = attempt_log.iter().all(|a| a.error == Some(ErrorKind::TimedOut));
+    if all_timed_out && attempt_log.len() >= usize::from(limit) {
+        let target =
+            attempt_log[0].host.map_or_else(|| "the X server".to_owned(), |ip| ip.to_string());
+        bail!(
+            "{} attempt(s) to reach {} all timed out, it's probably the wrong address",
+            attempt_log.len(),
+            target
+        );
+    }
+    Ok(())
+}
+
+/// Pick a random duration between zero and `max` (inclusive) to add to retry delays.
+///
+/// std's HashMap hasher is randomly seeded (with a different key for every RandomState), which is
+/// plenty random for this without pulling in an RNG crate.
+fn random_jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    let max_millis = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+    Duration::from_millis(random % max_millis.saturating_add(1))
+}
@@ -0,0 +1,349 @@
+//! Racing probes of several host candidates at once
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::detect::{detection_methods, method_candidate_ips, DetectionError};
+use crate::handshake::x_handshake;
+use crate::probe::{
+    attempt_count, attempt_timeout, candidate_deadline, connect_x, host_socket_addr, probe_pulse,
+    retry_probe, scan_displays, time_left, warm_up,
+};
+use crate::{
+    format_display_brackets, host_candidates, Args, Attempt, Candidate, DualStackPolicy,
+    HostRefused, IpFamily, Outcome,
+};
+
+/// What the race's threads report back
+enum Event {
+    /// A candidate was probed
+    Probed(Candidate, Result<(Option<u16>, Vec<Attempt>)>),
+    /// A detection method failed with --strict
+    DetectionFailed(anyhow::Error),
+}
+
+/// State shared by every thread in the race
+struct Race {
+    args: Args,
+    deadline: Option<Instant>,
+    start: Instant,
+    /// Set once a candidate connects, so the others stop
+    done: AtomicBool,
+    /// The candidates probed so far, and how many of each family (lead first) have been started
+    launched: Mutex<(Vec<IpAddr>, [u32; 2])>,
+}
+
+impl Race {
+    /// Start probing `candidate` in its own thread, unless it's already been started. Its probe
+    /// is staggered after the ones launched before it.
+    fn launch(self: &Arc<Self>, candidate: Candidate, tx: &Sender<Event>) {
+        let args = &self.args;
+        // with --family-delay each family is staggered on its own, the trailing one later
+        let (family, family_delay) = match args.family_delay {
+            Some(delay) if !args.lead_family.matches(&candidate.ip) => (1, delay),
+            _ => (0, Duration::ZERO),
+        };
+        let delay = {
+            let mut launched = self.launched.lock().unwrap_or_else(|e| e.into_inner());
+            let (ips, started) = &mut *launched;
+            if ips.contains(&candidate.ip) {
+                return;
+            }
+            ips.push(candidate.ip);
+            let delay = family_delay + args.stagger * started[family];
+            started[family] = started[family].saturating_add(1);
+            delay
+        };
+
+        let (race, tx) = (Arc::clone(self), tx.clone());
+        thread::spawn(move || {
+            sleep((race.start + delay).saturating_duration_since(Instant::now()));
+            if race.done.load(Ordering::Relaxed) {
+                return;
+            }
+            let args = &race.args;
+            if args.warmup {
+                warm_up(args, candidate.ip, attempt_timeout(args, time_left(race.deadline)));
+            }
+            let result = if args.connect_all_then_report_best {
+                probe_every_display(args, race.deadline, candidate.ip)
+            } else {
+                scan_displays(args, race.deadline, Some(candidate.ip), |display, timeout| {
+                    if race.done.load(Ordering::Relaxed) {
+                        return Err(io::Error::other("another candidate already connected"));
+                    }
+                    let sa =
+                        host_socket_addr(args, candidate.ip, display).map_err(io::Error::other)?;
+                    attempt_debug!("connecting to {}", sa);
+                    connect_x(args, &sa, timeout)
+                })
+            };
+            // the receiver is gone if someone else already won, that's fine
+            let _ = tx.send(Event::Probed(candidate, result));
+        });
+    }
+}
+
+/// Probe every display selected by `args` on `ip` for --connect-all-then-report-best, rather than
+/// stopping at the first one that connects. Returns the first display that connected and a record
+/// of every attempt, where the successful ones have the latencies to choose between.
+fn probe_every_display(
+    args: &Args,
+    deadline: Option<Instant>,
+    ip: IpAddr,
+) -> Result<(Option<u16>, Vec<Attempt>)> {
+    let mut attempt_log = Vec::new();
+    for display in args.displays() {
+        let sa = host_socket_addr(args, ip, display)?;
+        let display_deadline = candidate_deadline(args, deadline);
+        let (_, log) = retry_probe(args, display_deadline, Some(ip), display, |timeout| {
+            attempt_debug!("connecting to {}", sa);
+            connect_x(args, &sa, timeout)
+        })?;
+        attempt_log.extend(log);
+    }
+    let first = attempt_log.iter().find(|a| a.error.is_none()).map(|a| a.display);
+    Ok((first, attempt_log))
+}
+
+/// Build the Outcome for finding an X server on `display` of `candidate`
+fn found(
+    args: &Args,
+    candidate: Candidate,
+    display: u16,
+    attempt_log: Vec<Attempt>,
+) -> Result<Outcome> {
+    let server = if args.handshake {
+        let sa = host_socket_addr(args, candidate.ip, display)?;
+        Some(x_handshake(args, &sa)?)
+    } else {
+        None
+    };
+    Ok(Outcome {
+        display: Some(format_display_brackets(
+            Some(candidate.ip),
+            display,
+            args.screen,
+            !args.ipv6_no_brackets,
+        )),
+        host: Some(candidate.ip),
+        method: candidate.sources.first().copied(),
+        attempts: attempt_count(&attempt_log),
+        attempt_log,
+        server,
+        pulse_server: probe_pulse(args, candidate.ip),
+        ..Outcome::default()
+    })
+}
+
+/// A candidate that connected, on which display, and how long the connection took
+type Connected = (Candidate, u16, Duration);
+
+/// Whether the --dual-stack-policy choice between the candidates `connected` so far can't change
+/// anymore, however the rest of the race goes
+fn dual_stack_settled(policy: DualStackPolicy, connected: &[Connected]) -> bool {
+    let any = |family: IpFamily| connected.iter().any(|(c, ..)| family.matches(&c.ip));
+    match policy {
+        DualStackPolicy::PreferV4 => any(IpFamily::Ipv4),
+        DualStackPolicy::PreferV6 => any(IpFamily::Ipv6),
+        DualStackPolicy::Fastest => false,
+        DualStackPolicy::Both => any(IpFamily::Ipv4) && any(IpFamily::Ipv6),
+    }
+}
+
+/// Choose between the candidates that connected, in the order they did, with --dual-stack-policy.
+/// Returns the chosen one's index, and with `both` the index of the IPv6 one to go with an IPv4
+/// one.
+fn dual_stack_choice(
+    policy: DualStackPolicy,
+    connected: &[Connected],
+) -> (Option<usize>, Option<usize>) {
+    let first = |family: IpFamily| connected.iter().position(|(c, ..)| family.matches(&c.ip));
+    match policy {
+        DualStackPolicy::PreferV4 => (first(IpFamily::Ipv4).or(first(IpFamily::Ipv6)), None),
+        DualStackPolicy::PreferV6 => (first(IpFamily::Ipv6).or(first(IpFamily::Ipv4)), None),
+        DualStackPolicy::Fastest => {
+            let fastest = connected.iter().enumerate().min_by_key(|(_, (.., latency))| *latency);
+            (fastest.map(|(i, _)| i), None)
+        }
+        DualStackPolicy::Both => match (first(IpFamily::Ipv4), first(IpFamily::Ipv6)) {
+            (Some(v4), v6) => (Some(v4), v6),
+            (None, v6) => (v6, None),
+        },
+    }
+}
+
+/// Probe every host candidate found by the `args` methods, happy-eyeballs style (RFC 6555). Each
+/// candidate's probe starts `args.stagger` after the one before it, so that the preferred
+/// candidates get a head start without a slow one holding up the rest, and the first to connect
+/// wins. With `args.family_delay`, the two address families are staggered separately, and the
+/// family that isn't `args.lead_family` starts that much later.
+///
+/// The methods run at the same time too, and each candidate is probed as soon as its method finds
+/// it, so a slow method doesn't hold up a fast one that already connected. With
+/// `args.wait_all_methods`, every method finishes first and the candidates are probed in method
+/// order.
+///
+/// The first candidate to connect only wins if `args.dual_stack_policy` can't prefer one of the
+/// other family still being probed, see DualStackPolicy.
+///
+/// With `args.connect_all_then_report_best`, nothing wins early: every candidate is probed on
+/// every display, and the one that connected fastest is used.
+pub fn find_display_parallel(args: &Args) -> Result<Outcome> {
+    let race = Arc::new(Race {
+        args: args.clone(),
+        deadline: args.budget.map(|budget| Instant::now() + budget),
+        start: Instant::now(),
+        done: AtomicBool::new(false),
+        launched: Mutex::new((Vec::new(), [0; 2])),
+    });
+    let (tx, rx) = mpsc::channel();
+
+    if args.wait_all_methods || args.host.is_some() || args.host_file.is_some() {
+        for candidate in host_candidates(args)? {
+            race.launch(candidate, &tx);
+        }
+    } else {
+        for method in detection_methods(args) {
+            let (race, tx) = (Arc::clone(&race), tx.clone());
+            thread::spawn(move || match method_candidate_ips(&race.args, method) {
+                Ok(ips) => {
+                    for ip in ips {
+                        race.launch(Candidate { ip, sources: vec![method] }, &tx);
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Event::DetectionFailed(e));
+                }
+            });
+        }
+    }
+    // only the threads' senders are left, so the loop below ends once they've all finished
+    drop(tx);
+
+    let mut attempt_log = Vec::new();
+    let mut last_error = None;
+    let mut refused = false;
+    let mut best: Option<(Candidate, Attempt)> = None;
+    let mut connected: Vec<Connected> = Vec::new();
+    for event in rx {
+        let (candidate, result) = match event {
+            Event::Probed(candidate, result) => (candidate, result),
+            Event::DetectionFailed(e) => {
+                race.done.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        match result {
+            Ok((Some(_), log)) if args.connect_all_then_report_best => {
+                for attempt in log.iter().filter(|a| a.error.is_none()) {
+                    debug!(
+                        "{} display {} connected in {:?}",
+                        candidate.ip, attempt.display, attempt.latency
+                    );
+                    if best.as_ref().is_none_or(|(_, b)| attempt.latency < b.latency) {
+                        best = Some((candidate.clone(), attempt.clone()));
+                    }
+                }
+                attempt_log.extend(log);
+            }
+            Ok((Some(display), log)) => {
+                // the successful attempt is the last one
+                let latency = log.last().map_or(Duration::ZERO, |a| a.latency);
+                debug!("{} display {} connected in {:?}", candidate.ip, display, latency);
+                attempt_log.extend(log);
+                connected.push((candidate, display, latency));
+                if dual_stack_settled(args.dual_stack_policy, &connected) {
+                    race.done.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+            Ok((None, log)) => attempt_log.extend(log),
+            Err(e) => {
+                debug!("probing {} failed: {:#}", candidate.ip, e);
+                if e.is::<HostRefused>() {
+                    refused = true;
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    let policy = args.dual_stack_policy;
+    if let (Some(chosen), other) = dual_stack_choice(policy, &connected) {
+        let other_display = other.map(|i| {
+            let (candidate, display, _) = &connected[i];
+            format_display_brackets(
+                Some(candidate.ip),
+                *display,
+                args.screen,
+                !args.ipv6_no_brackets,
+            )
+        });
+        let (candidate, display, _) = connected.swap_remove(chosen);
+        debug!("{} chosen by --dual-stack-policy {:?}", candidate.ip, policy);
+        let outcome = found(args, candidate, display, attempt_log)?;
+        return Ok(Outcome { other_display, ..outcome });
+    }
+
+    if let Some((candidate, attempt)) = best {
+        debug!("{} display {} connected fastest", candidate.ip, attempt.display);
+        return found(args, candidate, attempt.display, attempt_log);
+    }
+
+    let launched = race.launched.lock().unwrap_or_else(|e| e.into_inner()).0.len();
+    if launched == 0 {
+        return Err(DetectionError(anyhow!("no host IP candidates found")).into());
+    }
+
+    // a reachable host is worth reporting even if other candidates just timed out, otherwise
+    // report an error only if every candidate failed with one, or it's just not found
+    match last_error {
+        _ if refused => Err(HostRefused.into()),
+        Some(e) if attempt_log.is_empty() => Err(e),
+        _ => {
+            Ok(Outcome { attempts: attempt_count(&attempt_log), attempt_log, ..Outcome::default() })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A candidate at `ip` that connected on display 1 in `ms` milliseconds
+    fn connected(ip: &str, ms: u64) -> Connected {
+        let candidate = Candidate { ip: ip.parse().unwrap(), sources: Vec::new() };
+        (candidate, 1, Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn default_policy_prefers_ipv4() {
+        let policy = Args::default().dual_stack_policy;
+        assert_eq!(policy, DualStackPolicy::PreferV4);
+        // an IPv6 host connecting first doesn't settle it while IPv4 ones might still connect
+        let mut race = vec![connected("fd00::1", 5)];
+        assert!(!dual_stack_settled(policy, &race));
+        assert_eq!(dual_stack_choice(policy, &race), (Some(0), None));
+        race.push(connected("172.28.160.1", 20));
+        assert!(dual_stack_settled(policy, &race));
+        assert_eq!(dual_stack_choice(policy, &race), (Some(1), None));
+    }
+
+    #[test]
+    fn other_policies() {
+        let race = [connected("172.28.160.1", 20), connected("fd00::1", 5)];
+        assert_eq!(dual_stack_choice(DualStackPolicy::PreferV6, &race), (Some(1), None));
+        assert_eq!(dual_stack_choice(DualStackPolicy::Fastest, &race), (Some(1), None));
+        assert_eq!(dual_stack_choice(DualStackPolicy::Both, &race), (Some(0), Some(1)));
+        assert!(!dual_stack_settled(DualStackPolicy::Fastest, &race));
+        assert!(dual_stack_settled(DualStackPolicy::Both, &race));
+    }
+}
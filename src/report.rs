@@ -0,0 +1,312 @@
+//! The --report document, collecting everything about a detection run for bug reports
+
+use std::fs;
+use std::net::IpAddr;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+
+use crate::detect::{
+    detection_methods, dns_tunneling_enabled, host_ip_from_method, mirrored_networking,
+};
+use crate::probe::{display_port, verifies_probes};
+use crate::{
+    find_display, local_displays, neighbor, parse_resolv_conf, running_in_wsl, take_warnings, Args,
+    Outcome,
+};
+
+/// Run every detection method in `args` on its own, then probe for an X server, and collect the
+/// results, timings, and some facts about the environment into a report. The probe reuses the
+/// host IP that detection would have picked from the method results, rather than running the
+/// methods a second time. The host's neighbor (ARP) entry is looked up after the probe, since a
+/// host with no resolved link-layer address can't be reached at all.
+///
+/// Errors from the methods and the probe are recorded in the report rather than returned. The
+/// outcome is returned alongside it, if there was one.
+pub fn detection_report(args: &Args) -> (Value, Option<Outcome>) {
+    let mut chosen = None;
+    let methods: Vec<Value> = detection_methods(args)
+        .into_iter()
+        .map(|method| {
+            let start = Instant::now();
+            let result = host_ip_from_method(args, method);
+            if let (None, Ok(ip)) = (chosen, &result) {
+                chosen = Some((*ip, method));
+            }
+            json!({
+                "method": method.name(),
+                "host": result.as_ref().ok().map(IpAddr::to_string),
+                "error": result.as_ref().err().map(|e| format!("{e:#}")),
+                "elapsed_ms": start.elapsed().as_secs_f64() * 1000.0,
+            })
+        })
+        .collect();
+
+    // only the TCP probe of a single host uses detection's answer
+    let single_host = args.host.is_none()
+        && args.host_file.is_none()
+        && !args.local
+        && args.unix_socket.is_none();
+    let start = Instant::now();
+    let result = match chosen {
+        Some((ip, method)) if single_host && !args.parallel => {
+            let mut probe_args = args.clone();
+            probe_args.host = Some(ip);
+            find_display(&probe_args).map(|outcome| Outcome { method: Some(method), ..outcome })
+        }
+        _ => find_display(args),
+    };
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let mut probe = match &result {
+        Ok(outcome) => outcome.to_json(),
+        Err(e) => json!({ "found": false, "error": format!("{e:#}") }),
+    };
+    probe["elapsed_ms"] = elapsed_ms.into();
+    if let Some(hint) = result.as_ref().ok().and_then(Outcome::hint) {
+        probe["hint"] = hint.into();
+    }
+
+    // looked up after probing, so the kernel has had a reason to resolve the host
+    let neighbor_host =
+        result.as_ref().ok().and_then(|outcome| outcome.host).or(chosen.map(|c| c.0));
+    let neighbor = neighbor_host.map(|ip| match neighbor(ip) {
+        Ok(Some(entry)) => json!({ "host": ip.to_string(), "entry": entry.to_json() }),
+        Ok(None) => json!({ "host": ip.to_string(), "entry": Value::Null }),
+        Err(e) => json!({ "host": ip.to_string(), "error": format!("{e:#}") }),
+    });
+
+    let report = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "environment": environment(),
+        "methods": methods,
+        "result": probe,
+        "neighbor": neighbor,
+        "warnings": take_warnings().iter().map(ToString::to_string).collect::<Vec<_>>(),
+    });
+    (report, result.ok())
+}
+
+/// Describe what detection with `args` would do, for --plan, without doing any of it: where the
+/// host IP comes from and which methods would find it, the displays and ports to probe, and the
+/// timeouts and retries to probe them with.
+pub fn execution_plan(args: &Args) -> Value {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let target = if args.local {
+        "local"
+    } else if args.unix_socket.is_some() {
+        "unix-socket"
+    } else {
+        "tcp"
+    };
+    let candidates = if target != "tcp" {
+        Value::Null
+    } else if args.host.is_some() {
+        "host".into()
+    } else if args.host_file.is_some() {
+        "host-file".into()
+    } else if args.parallel {
+        "parallel".into()
+    } else {
+        "first".into()
+    };
+    let methods: Vec<&str> = match candidates.as_str() {
+        Some("first" | "parallel") => {
+            detection_methods(args).into_iter().map(|m| m.name()).collect()
+        }
+        _ => Vec::new(),
+    };
+    let displays = args.displays();
+    let ports: Vec<u16> = match (target, args.port) {
+        ("tcp", Some(port)) => vec![port],
+        ("tcp", None) => displays.iter().filter_map(|&d| display_port(d).ok()).collect(),
+        _ => Vec::new(),
+    };
+    let retry_schedule: Option<Vec<f64>> =
+        args.retry_schedule.as_ref().map(|schedule| schedule.0.iter().copied().map(ms).collect());
+    json!({
+        "target": target,
+        "host": args.host.map(|ip| ip.to_string()),
+        "host_file": args.host_file.as_ref().map(|path| path.display().to_string()),
+        "unix_socket": args.unix_socket.as_ref().map(|path| path.display().to_string()),
+        "candidates": candidates,
+        "methods": methods,
+        "displays": displays,
+        "ports": ports,
+        "probe": {
+            "timeout_ms": ms(args.timeout),
+            "attempts": args.attempts(),
+            "retry_schedule_ms": retry_schedule,
+            "refused_wait_ms": ms(args.refused_wait.unwrap_or(args.timeout)),
+            "jitter_ms": ms(args.jitter),
+            "budget_ms": args.budget.map(ms),
+            "timeout_per_candidate_ms": args.timeout_per_candidate.map(ms),
+            "stagger_ms": args.parallel.then(|| ms(args.stagger)),
+            "verified": verifies_probes(args),
+            "validate_with": args.validate_with,
+            "handshake": args.handshake,
+        },
+        "validate": !args.no_validate,
+    })
+}
+
+/// Facts about the system that affect detection
+fn environment() -> Value {
+    let resolv_ns = fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|contents| parse_resolv_conf(&contents).ok());
+    json!({
+        "wsl": running_in_wsl(),
+        "iproute2_version": iproute2_version(),
+        "mirrored_networking": mirrored_networking(),
+        "dns_tunneling": dns_tunneling_enabled(),
+        "resolv_conf_nameserver": resolv_ns.map(|ip| ip.to_string()),
+        // e.g. systemd-resolved's 127.0.0.53, which is never the host
+        "resolv_conf_stub": resolv_ns.is_some_and(|ip| ip.is_loopback()),
+        "local_displays": local_displays(),
+    })
+}
+
+/// Get the iproute2 version from `ip -V`, which prints e.g. "ip utility, iproute2-6.1.0"
+fn iproute2_version() -> Option<String> {
+    let output = Command::new("ip").arg("-V").env("LC_ALL", "C").env("LANG", "C").output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .split([',', ' '])
+        .find_map(|word| word.strip_prefix("iproute2-"))
+        .map(|version| version.trim().to_owned())
+}
+
+/// Format a report as TOML. Objects become tables, arrays of objects become arrays of tables, and
+/// nulls are left out (also from arrays) since TOML has no way to write them. Objects in arrays
+/// that also hold other values are written as inline tables.
+pub fn report_to_toml(report: &Value) -> Result<String> {
+    let mut out = String::new();
+    match report {
+        Value::Object(table) => write_toml_table(&mut out, "", table),
+        other => bail!("expected a JSON object, got {other}"),
+    }
+    Ok(out)
+}
+
+/// Append `table` to `out` as TOML, with its sub-tables named under `prefix`
+fn write_toml_table(out: &mut String, prefix: &str, table: &Map<String, Value>) {
+    let is_table_array = |value: &Value| {
+        value.as_array().is_some_and(|a| {
+            let mut items = a.iter().filter(|item| !item.is_null()).peekable();
+            items.peek().is_some() && items.all(Value::is_object)
+        })
+    };
+
+    // plain keys have to come before any sub-tables
+    for (key, value) in table {
+        if value.is_object() || is_table_array(value) {
+            continue;
+        }
+        if let Some(value) = toml_value(value) {
+            *out += &format!("{} = {}\n", toml_key(key), value);
+        }
+    }
+    for (key, value) in table {
+        let name =
+            if prefix.is_empty() { toml_key(key) } else { format!("{prefix}.{}", toml_key(key)) };
+        match value {
+            Value::Object(sub) => {
+                *out += &format!("\n[{name}]\n");
+                write_toml_table(out, &name, sub);
+            }
+            Value::Array(items) if is_table_array(value) => {
+                for sub in items.iter().filter_map(Value::as_object) {
+                    *out += &format!("\n[[{name}]]\n");
+                    write_toml_table(out, &name, sub);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Quote a key if it isn't a TOML bare key
+fn toml_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        key.to_owned()
+    } else {
+        toml_string(key)
+    }
+}
+
+/// Format `value` as an inline TOML value, or None for a null
+fn toml_value(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::Null => return None,
+        Value::String(s) => toml_string(s),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().filter_map(toml_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(table) => {
+            let fields: Vec<String> = table
+                .iter()
+                .filter_map(|(key, value)| {
+                    Some(format!("{} = {}", toml_key(key), toml_value(value)?))
+                })
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        // numbers and booleans are written the same as in JSON
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+    })
+}
+
+/// Quote `s` as a TOML basic string. JSON's escapes are almost the same, but it leaves DEL as is.
+fn toml_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\t' => quoted += "\\t",
+            '\r' => quoted += "\\r",
+            c if c.is_control() => quoted += &format!("\\u{:04X}", u32::from(c)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_drops_nulls_and_inlines_mixed_arrays() {
+        let report = json!({
+            "name": "say \"hi\"\\\u{7f}",
+            "missing": null,
+            "ports": [6000, null, 6001],
+            "mixed": [1, {"a": null, "b": [true]}, "x"],
+            "odd key": 1.5,
+            "host": {"ip": "172.16.0.1", "none": null},
+            "methods": [{"method": "route"}, null, {"method": "bridge", "ip": null}],
+        });
+        // checked with Python's tomllib, which reads it back as the report without the nulls
+        let expected = r#"mixed = [1, {b = [true]}, "x"]
+name = "say \"hi\"\\\u007F"
+"odd key" = 1.5
+ports = [6000, 6001]
+
+[host]
+ip = "172.16.0.1"
+
+[[methods]]
+method = "route"
+
+[[methods]]
+method = "bridge"
+"#;
+        assert_eq!(report_to_toml(&report).unwrap(), expected);
+    }
+}
@@ -0,0 +1,53 @@
+//! TLS for X servers behind TLS termination, for --probe-protocol tls
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+use crate::Args;
+
+/// The client config with the system's CA certificates, which are only loaded once
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                debug!("failed to load a CA certificate: {}", e);
+            }
+            let mut roots = RootCertStore::empty();
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            debug!("loaded {} CA certificates, ignored {}", added, ignored);
+            let config =
+                ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// Start a TLS client connection to the host at `ip`. The certificate has to match the
+/// --hostname if there is one, otherwise the IP itself.
+pub(crate) fn client(args: &Args, ip: IpAddr) -> io::Result<ClientConnection> {
+    let name = match &args.hostname {
+        Some(hostname) => ServerName::try_from(hostname.clone())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+        None => ServerName::IpAddress(ip.into()),
+    };
+    ClientConnection::new(client_config(), name).map_err(io::Error::other)
+}
+
+/// Do the TLS handshake on a new probe connection, which is all there is to check that the other
+/// end speaks TLS. Handshake failures are InvalidData errors.
+pub(crate) fn handshake(args: &Args, stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(args.read_timeout()))?;
+    stream.set_write_timeout(Some(args.read_timeout()))?;
+    let mut conn = client(args, stream.peer_addr()?.ip())?;
+    while conn.is_handshaking() {
+        conn.complete_io(stream)?;
+    }
+    debug!("TLS handshake with {:?} done, {:?}", stream.peer_addr(), conn.protocol_version());
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Reading display numbers from an Xauthority file, for --from-xauth
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Get the display numbers that have entries in the Xauthority file, in the order they're listed.
+/// The file is $XAUTHORITY, or ~/.Xauthority if that's not set. A missing file has no displays.
+pub fn xauth_displays() -> Result<Vec<u16>> {
+    let path = match env::var_os("XAUTHORITY") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".Xauthority"))
+            .ok_or_else(|| anyhow!("neither XAUTHORITY nor HOME is set"))?,
+    };
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("{} doesn't exist", path.display());
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    parse_xauthority(&data).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Parse the contents of an Xauthority file and return the unique display numbers of its entries.
+///
+/// Each entry is a big-endian u16 address family followed by four length-prefixed fields: the
+/// address, display number (as decimal text), auth name, and auth data. Entries with a display
+/// number that isn't a number are skipped. This must never panic.
+pub fn parse_xauthority(data: &[u8]) -> Result<Vec<u16>> {
+    let mut displays = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let _family = take(&mut rest, 2)?;
+        let _address = take_field(&mut rest)?;
+        let number = take_field(&mut rest)?;
+        let _name = take_field(&mut rest)?;
+        let _data = take_field(&mut rest)?;
+
+        match std::str::from_utf8(number).ok().and_then(|n| n.parse::<u16>().ok()) {
+            Some(display) if !displays.contains(&display) => displays.push(display),
+            Some(_) => (),
+            None => debug!("skipping Xauthority entry with display number {:?}", number),
+        }
+    }
+    Ok(displays)
+}
+
+/// Split `len` bytes off the front of `rest`
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if rest.len() < len {
+        return Err(anyhow!("truncated entry"));
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(head)
+}
+
+/// Split a u16 length-prefixed field off the front of `rest`
+fn take_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = take(rest, 2)?;
+    take(rest, usize::from(u16::from_be_bytes([len[0], len[1]])))
+}
@@ -0,0 +1,329 @@
+//! End-to-end tests of the wsl2-get-display binary, probing listeners on localhost so that they
+//! don't depend on WSL or the network. Run `cargo test --features simulate` to include the tests
+//! of --simulate.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+use assert_cmd::cargo::cargo_bin_cmd;
+use assert_cmd::Command;
+use predicates::prelude::*;
+use serde_json::Value;
+
+/// The environment variables that the binary reads options from
+const ENV_VARS: &[&str] = &[
+    "WSL2_DISPLAY_TIMEOUT",
+    "WSL2_DISPLAY_RETRIES",
+    "WSL2_DISPLAY_NUMBER",
+    "WSL2_DISPLAY_METHOD",
+    "WSL2_DISPLAY_ASSUME_WSL",
+];
+
+/// The binary with a quick timeout and none of its environment variables, probing `port`
+fn binary(port: u16) -> Command {
+    let mut cmd = cargo_bin_cmd!();
+    for var in ENV_VARS {
+        cmd.env_remove(var);
+    }
+    cmd.args(["--assume-wsl", "--allow-loopback", "--timeout", "200"]);
+    cmd.args(["--port", &port.to_string()]);
+    cmd
+}
+
+/// binary(), probing localhost
+fn localhost(port: u16) -> Command {
+    let mut cmd = binary(port);
+    cmd.args(["--host", "127.0.0.1"]);
+    cmd
+}
+
+/// A directory for `test` to put files in, emptied first
+fn test_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("wsl2-get-display-{}-{test}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Put a fake `ip` command in `dir` that logs each run to `dir/ip.log` and says the default
+/// route is via localhost, and return the PATH to run the binary with so that it's used
+fn fake_ip(dir: &Path) -> OsString {
+    let script = dir.join("ip");
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\n\
+             echo \"$*\" >> '{}'\n\
+             echo '[{{\"dst\":\"default\",\"gateway\":\"127.0.0.1\",\"dev\":\"lo\"}}]'\n",
+            dir.join("ip.log").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut path = dir.as_os_str().to_owned();
+    if let Some(old) = env::var_os("PATH") {
+        path.push(":");
+        path.push(old);
+    }
+    path
+}
+
+/// A port that nothing is listening on, so connecting to it is refused
+fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Start a fake X server that accepts every connection setup, returning its port. It runs until
+/// the test process exits.
+fn fake_x_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            // plain probe connections hang up without sending a setup request
+            let mut request = [0u8; 12];
+            if stream.read_exact(&mut request).is_ok() {
+                let _ = stream.write_all(&setup_reply());
+            }
+        }
+    });
+    port
+}
+
+/// A successful little-endian X setup reply: protocol 11.0, release 12101004, and vendor
+/// "Fake X" padded to 4 bytes, with no screens or formats
+fn setup_reply() -> Vec<u8> {
+    let vendor = b"Fake X\0\0";
+    let mut body = vec![0u8; 32];
+    body[..4].copy_from_slice(&12101004u32.to_le_bytes());
+    body[16..18].copy_from_slice(&6u16.to_le_bytes());
+    body.extend_from_slice(vendor);
+    let mut reply = vec![1, 0, 11, 0, 0, 0];
+    reply.extend_from_slice(&u16::try_from(body.len() / 4).unwrap().to_le_bytes());
+    reply.extend(body);
+    reply
+}
+
+#[test]
+fn found() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    localhost(port).arg("1").assert().success().stdout("127.0.0.1:1\n");
+}
+
+#[test]
+fn found_export() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    localhost(port)
+        .args(["--export", "--shell", "fish", "0"])
+        .assert()
+        .success()
+        .stdout("set -gx DISPLAY '127.0.0.1:0'\n");
+}
+
+#[test]
+fn not_found() {
+    localhost(closed_port()).assert().code(1).stdout("");
+}
+
+#[test]
+fn host_refused() {
+    localhost(closed_port()).arg("--refuse-is-success").assert().code(3).stdout("");
+}
+
+#[test]
+fn detection_error() {
+    cargo_bin_cmd!()
+        .args(["--assume-wsl", "--host-file", "/nonexistent/wsl2-get-display-hosts"])
+        .assert()
+        .code(2)
+        .stdout("")
+        .stderr(predicate::str::contains("/nonexistent/wsl2-get-display-hosts"));
+}
+
+#[test]
+fn bad_arguments() {
+    cargo_bin_cmd!().args(["--icon-found", "y"]).assert().code(2).stdout("");
+}
+
+#[test]
+fn json() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let output = localhost(port).args(["--json", "2"]).assert().success();
+    let js: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(js["display"], "127.0.0.1:2");
+    assert_eq!(js["host"], "127.0.0.1");
+    assert_eq!(js["attempts"], 1);
+}
+
+#[test]
+fn json_not_found() {
+    let output = localhost(closed_port()).arg("--json").assert().code(1);
+    let js: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(js["display"], Value::Null);
+    assert_eq!(js["attempts"], 1);
+}
+
+#[test]
+fn json_errors() {
+    cargo_bin_cmd!()
+        .args(["--assume-wsl", "--json-errors", "--host-file", "/nonexistent/hosts"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(r#""found":false"#));
+}
+
+#[test]
+fn icon() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    localhost(port).args(["--icon", "1"]).assert().success().stdout("✓:1\n");
+    localhost(closed_port()).args(["--icon", "--icon-missing", "-"]).assert().code(1).stdout("-\n");
+}
+
+#[test]
+fn handshake() {
+    let output = localhost(fake_x_server()).args(["--handshake", "--json", "1"]).assert().success();
+    let js: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(js["display"], "127.0.0.1:1");
+    assert_eq!(js["server"]["accepted"], true);
+    assert_eq!(js["server"]["protocol_version"], "11.0");
+    assert_eq!(js["server"]["release"], 12101004);
+    assert_eq!(js["server"]["vendor"], "Fake X");
+    assert_eq!(js["server"]["reason"], Value::Null);
+}
+
+#[test]
+fn strict_connect_accepts_x_server() {
+    localhost(fake_x_server()).args(["--strict-connect", "1"]).assert().success();
+}
+
+#[test]
+fn handshake_rejects_non_x_server() {
+    // accepts, then hangs up without an X setup reply
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || drop(listener.accept()));
+    localhost(port).arg("--handshake").assert().failure().stdout("");
+    // unblock accept() if the client never connected
+    let _ = TcpStream::connect(("127.0.0.1", port));
+    server.join().unwrap();
+}
+
+#[cfg(feature = "simulate")]
+#[test]
+fn simulate() {
+    localhost(closed_port()).args(["--simulate", "ok"]).assert().success().stdout("127.0.0.1:1\n");
+    localhost(closed_port()).args(["--simulate", "unreachable"]).assert().failure().stdout("");
+}
+
+#[test]
+fn allow_subnet_rejects_host() {
+    for mode in ["--retries=1", "--parallel"] {
+        localhost(closed_port())
+            .args([mode, "--allow-subnet", "10.0.0.0/8"])
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("not in an allowed subnet"));
+    }
+}
+
+#[test]
+fn bitmask() {
+    // every display is on the same --port, so they all have an X server or none do
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    localhost(port).args(["--bitmask", "--scan", "0,1"]).assert().code(3).stdout("").stderr("");
+    localhost(port).args(["--bitmask", "--scan", "0..8"]).assert().code(255).stdout("");
+    localhost(closed_port()).args(["--bitmask", "--scan", "0,1"]).assert().code(0).stdout("");
+    localhost(port)
+        .args(["--bitmask", "--scan", "0..9"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("at most 8 displays"));
+}
+
+#[test]
+fn route_detected_once_per_scan() {
+    for mode in ["--retries=1", "--parallel"] {
+        let dir = test_dir(&format!("route-once{mode}"));
+        binary(closed_port())
+            .env("PATH", fake_ip(&dir))
+            .args([mode, "--method", "route", "--scan", "0..8", "--refused-wait", "0"])
+            .assert()
+            .code(1);
+        let log = fs::read_to_string(dir.join("ip.log")).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), ["-4 -json route show default"], "{mode}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn link_local_host_needs_zone() {
+    for mode in ["--retries=1", "--parallel"] {
+        binary(closed_port())
+            .args([mode, "--host", "fe80::1"])
+            .assert()
+            .code(2)
+            .stdout("")
+            .stderr(predicate::str::contains("IPv6 link-local address"))
+            .stderr(predicate::str::contains("fe80::1%eth0"));
+    }
+}
+
+#[test]
+fn host_file_not_found_keeps_last_host() {
+    let dir = test_dir("host-file-last");
+    let hosts = dir.join("hosts");
+    fs::write(&hosts, "127.0.0.1\n127.0.0.2\n").unwrap();
+    let output = binary(closed_port())
+        .args(["--json-verbose", "--refused-wait", "0", "--host-file"])
+        .arg(&hosts)
+        .assert()
+        .code(1);
+    let js: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(js["display"], Value::Null);
+    assert_eq!(js["host"], "127.0.0.2");
+    let tried: Vec<&Value> =
+        js["attempts"].as_array().unwrap().iter().map(|a| &a["host"]).collect();
+    assert_eq!(tried, ["127.0.0.1", "127.0.0.2"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn json_verbose_reports_port_override() {
+    let port = closed_port();
+    let output = localhost(port)
+        .args(["--json-verbose", "--refused-wait", "0", "--scan", "0,1"])
+        .assert()
+        .code(1);
+    let js: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    let ports: Vec<&Value> =
+        js["attempts"].as_array().unwrap().iter().map(|a| &a["port"]).collect();
+    assert_eq!(ports, [port, port]);
+}
+
+#[test]
+fn emit_function_from_response_file() {
+    let dir = test_dir("emit-function");
+    let response = dir.join("args");
+    fs::write(&response, "--emit-function\n--scan\n0,2\n").unwrap();
+    let output =
+        localhost(closed_port()).arg(format!("@{}", response.display())).assert().success();
+    let function = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+    assert!(function.contains(" '--scan' '0,2' "), "{function}");
+    assert!(function.contains(" '--export'"), "{function}");
+    assert!(!function.contains("emit-function"), "{function}");
+    assert!(!function.contains(response.to_str().unwrap()), "{function}");
+    fs::remove_dir_all(dir).unwrap();
+}